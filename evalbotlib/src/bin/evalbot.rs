@@ -0,0 +1,173 @@
+use std::env;
+use std::fs;
+use std::process;
+
+use evalbotlib::{EvalRequest, EvalService, Severity, SyscallList};
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: evalbot check-config [evalbot.toml]
+       evalbot syscalls check LIST...
+       evalbot syscalls merge LIST...
+       evalbot syscalls diff LIST LIST
+       evalbot syscalls learn evalbot.toml LANGUAGE SAMPLE..."
+    );
+    process::exit(2);
+}
+
+fn check_config(path: &str) -> i32 {
+    let toml = match fs::read_to_string(path) {
+        Ok(toml) => toml,
+        Err(e) => {
+            eprintln!("could not read {}: {}", path, e);
+            return 1;
+        }
+    };
+
+    let problems = EvalService::validate(&toml);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    let errors = problems
+        .iter()
+        .filter(|p| p.severity == Severity::Error)
+        .count();
+    println!(
+        "{}: {} errors, {} warnings",
+        path,
+        errors,
+        problems.len() - errors
+    );
+    if errors > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+fn check_syscalls(paths: &[&str]) -> i32 {
+    let mut code = 0;
+    for path in paths {
+        let list = match fs::read_to_string(path) {
+            Ok(list) => list,
+            Err(e) => {
+                eprintln!("could not read {}: {}", path, e);
+                code = 1;
+                continue;
+            }
+        };
+        let (syscalls, problems) = SyscallList::parse(&list);
+        for problem in &problems {
+            println!("{}: {}", path, problem);
+        }
+        let errors = problems
+            .iter()
+            .filter(|p| p.severity == Severity::Error)
+            .count();
+        println!(
+            "{}: {} syscalls, {} errors, {} warnings",
+            path,
+            syscalls.len(),
+            errors,
+            problems.len() - errors
+        );
+        if errors > 0 {
+            code = 1;
+        }
+    }
+    code
+}
+
+fn read_syscalls(path: &str) -> SyscallList {
+    SyscallList::read(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    })
+}
+
+fn merge_syscalls(paths: &[&str]) -> i32 {
+    let mut merged = SyscallList::default();
+    for path in paths {
+        merged.merge(&read_syscalls(path));
+    }
+    print!("{}", merged);
+    0
+}
+
+fn diff_syscalls(a: &str, b: &str) -> i32 {
+    let (only_a, only_b) = read_syscalls(a).diff(&read_syscalls(b));
+    for name in only_a.names() {
+        println!("- {}", name);
+    }
+    for name in only_b.names() {
+        println!("+ {}", name);
+    }
+    if only_a.is_empty() && only_b.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+fn learn_syscalls(config: &str, lang: &str, samples: &[&str]) -> i32 {
+    let service = match fs::read_to_string(config)
+        .map_err(|e| e.to_string())
+        .and_then(|toml| EvalService::from_toml(&toml).map_err(|e| e.to_string()))
+    {
+        Ok(service) => service,
+        Err(e) => {
+            eprintln!("could not load {}: {}", config, e);
+            return 1;
+        }
+    };
+    let lang = match service.get(lang) {
+        Some(lang) => lang,
+        None => {
+            eprintln!("no language {}", lang);
+            return 1;
+        }
+    };
+    let mut learned = SyscallList::default();
+    let mut code = 0;
+    for sample in samples {
+        let request = match fs::read_to_string(sample) {
+            Ok(code) => EvalRequest::new(code),
+            Err(e) => {
+                eprintln!("could not read {}: {}", sample, e);
+                return 1;
+            }
+        };
+        match lang.trace_syscalls(&request) {
+            Ok((syscalls, status)) => {
+                eprintln!("{}: {}, {} syscalls", sample, status, syscalls.len());
+                // a sample that failed may not have got as far as it should have
+                if !status.success() {
+                    code = 1;
+                }
+                learned.merge(&syscalls);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", sample, e);
+                return 1;
+            }
+        }
+    }
+    print!("{}", learned);
+    code
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let code = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["check-config"] => check_config("evalbot.toml"),
+        ["check-config", path] => check_config(path),
+        ["syscalls", "check", ref paths @ ..] if !paths.is_empty() => check_syscalls(paths),
+        ["syscalls", "merge", ref paths @ ..] if !paths.is_empty() => merge_syscalls(paths),
+        ["syscalls", "diff", a, b] => diff_syscalls(a, b),
+        ["syscalls", "learn", config, lang, ref samples @ ..] if !samples.is_empty() => {
+            learn_syscalls(config, lang, samples)
+        }
+        _ => usage(),
+    };
+    process::exit(code);
+}
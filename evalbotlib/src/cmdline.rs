@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::env;
+
+use crate::template;
+
+/// The `cmdline` of a language, with the placeholders that do not change between evaluations
+/// already substituted.
+///
+/// These placeholders can appear anywhere in an argument:
+///
+/// * `{TIMEOUT}`: the timeout in seconds after `timeout_prefix`, or 0 if there is none
+/// * `{MEMORY}`: the language's `memory_limit`, or its sandbox's
+/// * `{LANG}`: the language's name
+/// * `{CONTEXT}`: the key of the context the evaluation is for, if any
+/// * `{VARIANT}`: the name of the template the code was wrapped in, if any
+/// * `{WORKDIR}`: a directory created for the evaluation and removed after it
+/// * `{NAME}`: the language's variable `NAME`
+/// * `{ENV:NAME}`: the environment variable `NAME`
+///
+/// `{CODE}` and `{ARGS}` stand for the code and the program's arguments, and must be whole
+/// arguments. Braces after a `$` are left alone, so that shell scripts can use `${VAR}`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Cmdline(Vec<Arg>);
+
+#[derive(Clone, PartialEq, Debug)]
+enum Arg {
+    Code,
+    Args,
+    Pieces(Vec<Piece>),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Piece {
+    Text(String),
+    Timeout,
+    Context,
+    Variant,
+    Workdir,
+}
+
+/// What the placeholders that change between evaluations stand for.
+pub struct Values<'a> {
+    pub timeout: Option<usize>,
+    pub context: Option<&'a str>,
+    pub variant: Option<&'a str>,
+    pub workdir: Option<&'a str>,
+    pub code: &'a str,
+    pub args: &'a [String],
+}
+
+impl Cmdline {
+    pub fn parse(
+        cmdline: &[String],
+        lang: &str,
+        timeout_prefix: Option<&str>,
+        memory_limit: Option<u64>,
+        vars: &HashMap<String, String>,
+    ) -> Result<Self, String> {
+        if cmdline.is_empty() {
+            return Err("empty cmdline".to_owned());
+        }
+        let mut args = Vec::new();
+        for arg in cmdline {
+            args.push(match arg.as_str() {
+                "{CODE}" => Arg::Code,
+                "{ARGS}" => Arg::Args,
+                _ => Arg::Pieces(parse_arg(arg, lang, timeout_prefix, memory_limit, vars)?),
+            });
+        }
+        Ok(Cmdline(args))
+    }
+
+    /// The program to run, unless it depends on the evaluation.
+    pub fn program(&self) -> Option<&str> {
+        match self.0.first() {
+            Some(Arg::Pieces(pieces)) => match pieces.as_slice() {
+                [Piece::Text(text)] => Some(text),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether the code is passed as an argument rather than on stdin.
+    pub fn has_code(&self) -> bool {
+        self.0.contains(&Arg::Code)
+    }
+
+    pub fn has_workdir(&self) -> bool {
+        self.0.iter().any(|arg| match arg {
+            Arg::Pieces(pieces) => pieces.contains(&Piece::Workdir),
+            _ => false,
+        })
+    }
+
+    /// Returns the program and its arguments. The program's arguments are appended if there is no
+    /// `{ARGS}`.
+    pub fn expand(&self, values: &Values) -> Vec<String> {
+        let mut r = Vec::new();
+        for arg in &self.0 {
+            match arg {
+                Arg::Code => r.push(values.code.to_owned()),
+                Arg::Args => r.extend(values.args.iter().cloned()),
+                Arg::Pieces(pieces) => {
+                    let mut s = String::new();
+                    for piece in pieces {
+                        match piece {
+                            Piece::Text(text) => s.push_str(text),
+                            Piece::Timeout => s.push_str(&values.timeout.unwrap_or(0).to_string()),
+                            Piece::Context => s.push_str(values.context.unwrap_or("")),
+                            Piece::Variant => s.push_str(values.variant.unwrap_or("")),
+                            Piece::Workdir => s.push_str(values.workdir.unwrap_or("")),
+                        }
+                    }
+                    r.push(s);
+                }
+            }
+        }
+        if !self.0.contains(&Arg::Args) {
+            r.extend(values.args.iter().cloned());
+        }
+        r
+    }
+}
+
+fn parse_arg(
+    arg: &str,
+    lang: &str,
+    timeout_prefix: Option<&str>,
+    memory_limit: Option<u64>,
+    vars: &HashMap<String, String>,
+) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let name = match rest.find('}') {
+            Some(end) if !text.ends_with('$') => &rest[1..end],
+            _ => {
+                text.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let dynamic = match name {
+            "TIMEOUT" => {
+                text.push_str(timeout_prefix.unwrap_or(""));
+                Some(Piece::Timeout)
+            }
+            "CONTEXT" => Some(Piece::Context),
+            "VARIANT" => Some(Piece::Variant),
+            "WORKDIR" => Some(Piece::Workdir),
+            "MEMORY" => {
+                let memory_limit =
+                    memory_limit.ok_or("{MEMORY} is used, but memory_limit is not set")?;
+                text.push_str(&memory_limit.to_string());
+                None
+            }
+            "LANG" => {
+                text.push_str(lang);
+                None
+            }
+            "CODE" | "ARGS" => return Err(format!("{{{}}} must be a whole argument", name)),
+            _ if name.starts_with("ENV:") => {
+                let var = &name[4..];
+                let value =
+                    env::var(var).map_err(|e| format!("environment variable {}: {}", var, e))?;
+                text.push_str(&value);
+                None
+            }
+            _ if template::is_placeholder(name) => match vars.get(name) {
+                Some(value) => {
+                    text.push_str(value);
+                    None
+                }
+                None => return Err(format!("unknown placeholder {{{}}}", name)),
+            },
+            _ => {
+                text.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        if let Some(piece) = dynamic {
+            if !text.is_empty() {
+                pieces.push(Piece::Text(std::mem::take(&mut text)));
+            }
+            pieces.push(piece);
+        }
+        rest = &rest[name.len() + 2..];
+    }
+    text.push_str(rest);
+    if !text.is_empty() || pieces.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{Cmdline, Values};
+
+    fn cmdline(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_cmdline() {
+        let mut vars = HashMap::new();
+        vars.insert("SYSCALLS".to_owned(), "rust_syscalls".to_owned());
+        let parsed = Cmdline::parse(
+            &cmdline(&[
+                "playpen",
+                "--syscalls-file={SYSCALLS}",
+                "--memory-limit={MEMORY}",
+                "{TIMEOUT}",
+                "{LANG}/{VARIANT}@{CONTEXT}",
+                "echo ${HOME} {}",
+                "{CODE}",
+            ]),
+            "rs",
+            Some("--timeout="),
+            Some(128),
+            &vars,
+        )
+        .unwrap();
+        assert_eq!(parsed.program(), Some("playpen"));
+        assert!(parsed.has_code());
+        assert!(!parsed.has_workdir());
+        let args = ["a".to_owned()];
+        let values = Values {
+            timeout: Some(10),
+            context: Some("tg1"),
+            variant: None,
+            workdir: None,
+            code: "fn main() {}",
+            args: &args,
+        };
+        assert_eq!(
+            parsed.expand(&values),
+            cmdline(&[
+                "playpen",
+                "--syscalls-file=rust_syscalls",
+                "--memory-limit=128",
+                "--timeout=10",
+                "rs/@tg1",
+                "echo ${HOME} {}",
+                "fn main() {}",
+                "a",
+            ])
+        );
+
+        let parse = |arg: &str| Cmdline::parse(&cmdline(&["sh", arg]), "sh", None, None, &vars);
+        assert!(parse("{MISSING}").is_err());
+        assert!(parse("{MEMORY}").is_err());
+        assert!(parse("-c{CODE}").is_err());
+        assert!(parse("{ENV:EVALBOT_SURELY_UNSET}").is_err());
+    }
+}
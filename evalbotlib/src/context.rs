@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// A context kept alive by a persistent evaluator.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ContextInfo {
+    pub key: String,
+    /// Approximate memory used by the context in bytes, if the evaluator can tell.
+    pub memory: Option<u64>,
+}
+
+/// Remembers when each context was last used, so that idle ones can be dropped.
+#[derive(Debug)]
+pub struct Contexts {
+    ttl: Duration,
+    last_used: Mutex<HashMap<String, Instant>>,
+}
+
+impl Contexts {
+    /// Returns `prev` if it has the same TTL, so that contexts used before a reload still
+    /// expire.
+    pub fn reuse(prev: Option<&Arc<Contexts>>, ttl: Option<u64>) -> Option<Arc<Self>> {
+        let ttl = Duration::from_secs(ttl.filter(|&n| n != 0)?);
+        match prev {
+            Some(prev) if prev.ttl == ttl => Some(prev.clone()),
+            _ => Some(Arc::new(Contexts {
+                ttl,
+                last_used: Mutex::new(HashMap::new()),
+            })),
+        }
+    }
+
+    pub fn touch(&self, key: &str) {
+        self.lock().insert(key.to_owned(), Instant::now());
+    }
+
+    pub fn forget(&self, key: &str) {
+        self.lock().remove(key);
+    }
+
+    /// Forgets and returns the contexts that have not been used within the TTL.
+    pub fn take_expired(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.lock().retain(|key, &mut used| {
+            let keep = now.duration_since(used) < self.ttl;
+            if !keep {
+                expired.push(key.clone());
+            }
+            keep
+        });
+        expired
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        self.last_used
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl PartialEq for Contexts {
+    fn eq(&self, other: &Self) -> bool {
+        self.ttl == other.ttl
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::Contexts;
+
+    #[test]
+    fn test_take_expired() {
+        assert!(Contexts::reuse(None, None).is_none());
+        assert!(Contexts::reuse(None, Some(0)).is_none());
+
+        let contexts = Contexts::reuse(None, Some(60)).unwrap();
+        contexts.touch("fresh");
+        contexts
+            .lock()
+            .insert("stale".to_owned(), Instant::now() - Duration::from_secs(61));
+        assert_eq!(contexts.take_expired(), vec!["stale".to_owned()]);
+        // expired contexts are only returned once
+        assert!(contexts.take_expired().is_empty());
+        assert!(contexts.lock().contains_key("fresh"));
+
+        let same = Contexts::reuse(Some(&contexts), Some(60)).unwrap();
+        assert!(Arc::ptr_eq(&same, &contexts));
+        let other = Contexts::reuse(Some(&contexts), Some(30)).unwrap();
+        assert!(!Arc::ptr_eq(&other, &contexts));
+    }
+}
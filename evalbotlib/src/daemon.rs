@@ -0,0 +1,245 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::{DaemonCfg, EvalError};
+
+const DEFAULT_RESTART_DELAY: u64 = 1;
+const DEFAULT_START_TIMEOUT: u64 = 30;
+
+/// A persistent evaluator run by evalbotlib itself, rather than by systemd.
+///
+/// The socket is bound here and handed to the daemon as file descriptor 3, like a systemd socket
+/// unit would. The daemon is started on first use and restarted when it exits or is killed after
+/// a timeout. Dropping the `Daemon` kills it.
+#[derive(Debug)]
+pub struct Daemon {
+    cfg: DaemonCfg,
+    socket_addr: String,
+    state: Arc<watch::Sender<State>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    Starting,
+    /// Ready to take requests. The number counts how many times the daemon was started.
+    Ready(u64),
+}
+
+/// Kills the daemon and everything it started when the supervisor is done with it.
+struct ChildGuard(Child);
+
+impl Daemon {
+    /// Returns `prev` if it runs the same daemon on the same socket, so that a reload does not
+    /// restart it.
+    pub fn reuse(
+        prev: Option<&Arc<Daemon>>,
+        cfg: Option<&DaemonCfg>,
+        socket_addr: &str,
+    ) -> Option<Arc<Self>> {
+        let cfg = cfg?;
+        match prev {
+            Some(prev) if prev.cfg == *cfg && prev.socket_addr == socket_addr => Some(prev.clone()),
+            _ => Some(Arc::new(Daemon {
+                cfg: cfg.clone(),
+                socket_addr: socket_addr.to_owned(),
+                state: Arc::new(watch::channel(State::Starting).0),
+                task: Mutex::new(None),
+            })),
+        }
+    }
+
+    /// Starts the daemon if it is not running yet and waits until it can take requests. Returns
+    /// the generation to pass to `restart`.
+    pub async fn ready(&self) -> Result<u64, EvalError> {
+        self.start();
+        let mut rx = self.state.subscribe();
+        let ready = async {
+            match *rx.wait_for(|s| matches!(s, State::Ready(_))).await.ok()? {
+                State::Ready(generation) => Some(generation),
+                State::Starting => None,
+            }
+        };
+        match time::timeout(self.start_timeout(), ready).await {
+            Ok(Some(generation)) => Ok(generation),
+            _ => Err(EvalError::Connect(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "evaluator daemon is not ready",
+            ))),
+        }
+    }
+
+    /// Kills the daemon so that it is started again, unless it was already restarted since
+    /// `generation`.
+    pub fn restart(&self, generation: u64) {
+        self.state.send_if_modified(|s| {
+            if *s == State::Ready(generation) {
+                *s = State::Starting;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    fn start(&self) {
+        let mut task = self.task.lock().unwrap_or_else(PoisonError::into_inner);
+        if task.is_none() {
+            *task = Some(tokio::spawn(supervise(
+                self.cfg.clone(),
+                self.socket_addr.clone(),
+                self.state.clone(),
+            )));
+        }
+    }
+
+    fn start_timeout(&self) -> Duration {
+        Duration::from_secs(self.cfg.start_timeout.unwrap_or(DEFAULT_START_TIMEOUT))
+    }
+}
+
+impl PartialEq for Daemon {
+    fn eq(&self, other: &Self) -> bool {
+        self.cfg == other.cfg && self.socket_addr == other.socket_addr
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let task = self.task.get_mut().unwrap_or_else(PoisonError::into_inner);
+        if let Some(task) = task.take() {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0.id() {
+            // the daemon leads its own process group
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+            }
+        }
+    }
+}
+
+async fn supervise(cfg: DaemonCfg, socket_addr: String, state: Arc<watch::Sender<State>>) {
+    let restart_delay = Duration::from_secs(cfg.restart_delay.unwrap_or(DEFAULT_RESTART_DELAY));
+    let start_timeout = Duration::from_secs(cfg.start_timeout.unwrap_or(DEFAULT_START_TIMEOUT));
+    let listener = loop {
+        match bind(&socket_addr) {
+            Ok(listener) => break listener,
+            Err(e) => error!("failed to bind {}: {}", socket_addr, e),
+        }
+        time::sleep(restart_delay).await;
+    };
+
+    let mut generation = 0;
+    let mut rx = state.subscribe();
+    loop {
+        match spawn(&cfg, &listener) {
+            Ok(child) => {
+                let mut child = ChildGuard(child);
+                generation += 1;
+                info!("started {:?} on {}", cfg.cmdline, socket_addr);
+                let started = tokio::select! {
+                    res = time::timeout(start_timeout, probe(&socket_addr)) => match res {
+                        Ok(Ok(())) => true,
+                        Ok(Err(e)) => {
+                            warn!("daemon on {} failed to accept a connection: {}", socket_addr, e);
+                            false
+                        }
+                        Err(_) => {
+                            warn!("daemon on {} did not start in time", socket_addr);
+                            false
+                        }
+                    },
+                    status = child.0.wait() => {
+                        warn!("daemon on {} exited while starting: {:?}", socket_addr, status);
+                        false
+                    }
+                };
+                if started {
+                    state.send_replace(State::Ready(generation));
+                    tokio::select! {
+                        status = child.0.wait() => {
+                            state.send_replace(State::Starting);
+                            warn!("daemon on {} exited: {:?}", socket_addr, status);
+                        }
+                        _ = rx.wait_for(|s| *s != State::Ready(generation)) => {
+                            info!("restarting daemon on {}", socket_addr);
+                        }
+                    }
+                }
+            }
+            Err(e) => error!("failed to spawn {:?}: {}", cfg.cmdline, e),
+        }
+        time::sleep(restart_delay).await;
+    }
+}
+
+fn bind(socket_addr: &str) -> io::Result<UnixListener> {
+    // a socket left behind by an earlier run would make bind fail
+    match fs::symlink_metadata(socket_addr) {
+        Ok(m) if m.file_type().is_socket() => fs::remove_file(socket_addr)?,
+        _ => {}
+    }
+    UnixListener::bind(socket_addr)
+}
+
+fn spawn(cfg: &DaemonCfg, listener: &UnixListener) -> io::Result<Child> {
+    let path = cfg
+        .cmdline
+        .first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty cmdline"))?;
+    let fd = listener.as_raw_fd();
+    let mut cmd = Command::new(path);
+    cmd.args(cfg.cmdline.iter().skip(1))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .process_group(0);
+    unsafe {
+        cmd.pre_exec(move || {
+            // dup2 clears close-on-exec on the new descriptor, but does nothing if it is the same
+            let res = if fd == 3 {
+                libc::fcntl(3, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if res == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+    debug!("spawning {:?}", cmd);
+    cmd.spawn()
+}
+
+/// Connects without sending anything, and waits for the daemon to accept the connection and close
+/// it. Connecting alone succeeds as soon as the socket is bound, before the daemon is listening.
+async fn probe(socket_addr: &str) -> Result<(), EvalError> {
+    let mut conn = UnixStream::connect(socket_addr)
+        .await
+        .map_err(EvalError::Connect)?;
+    conn.shutdown().await.map_err(EvalError::Io)?;
+    let mut buf = [0; 64];
+    while conn.read(&mut buf).await.map_err(EvalError::Io)? > 0 {}
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// An error that prevented an evaluation (or loading the configuration) from completing.
+///
+/// A program that ran and failed is not an error; see [`EvalResult`](crate::EvalResult).
+#[derive(Debug)]
+pub enum EvalError {
+    /// The configuration could not be parsed or is invalid.
+    Config(String),
+    /// The evaluator process could not be started.
+    Spawn(io::Error),
+    /// The evaluator daemon could not be reached.
+    Connect(io::Error),
+    /// Reading from or writing to a file, process or daemon failed.
+    Io(io::Error),
+    /// The evaluation did not finish within its time limit.
+    Timeout,
+    /// The evaluator responded with something that does not follow the protocol.
+    Protocol(String),
+    /// The evaluator reported an error of its own instead of a result.
+    Evaluator(String),
+    /// No language with this name is configured.
+    UnknownLanguage(String),
+    /// The language has no template with this name.
+    UnknownTemplate(String),
+    /// Too many evaluations are running or queued already.
+    Busy,
+    /// The language's backend cannot do what was asked.
+    Unsupported(String),
+    /// The evaluator lost the context, and replaying its journal to rebuild it failed, so the
+    /// code was not evaluated. The journal is forgotten, so the next evaluation starts over.
+    Replay(Box<EvalError>),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::Config(e) => write!(f, "invalid configuration: {}", e),
+            EvalError::Spawn(e) => write!(f, "failed to exec: {}", e),
+            EvalError::Connect(e) => write!(f, "error connecting: {}", e),
+            EvalError::Io(e) => write!(f, "I/O error: {}", e),
+            EvalError::Timeout => f.write_str("time limit exceeded"),
+            EvalError::Protocol(e) => write!(f, "protocol error: {}", e),
+            EvalError::Evaluator(e) => write!(f, "evaluator error: {}", e),
+            EvalError::UnknownLanguage(l) => write!(f, "unknown language: {}", l),
+            EvalError::UnknownTemplate(t) => write!(f, "unknown template: {}", t),
+            EvalError::Busy => f.write_str("too many evaluations running"),
+            EvalError::Unsupported(e) => write!(f, "not supported: {}", e),
+            EvalError::Replay(e) => write!(f, "failed to rebuild the context: {}", e),
+        }
+    }
+}
+
+impl Error for EvalError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EvalError::Spawn(e) | EvalError::Connect(e) | EvalError::Io(e) => Some(e),
+            EvalError::Replay(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
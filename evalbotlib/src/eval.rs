@@ -1,219 +1,563 @@
-use std::io::Cursor;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::io;
 use std::os::unix::process::ExitStatusExt;
-use std::process::Stdio;
+use std::path::PathBuf;
+use std::process::{self, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use bytes::{Buf, BufMut, BytesMut};
-use log::{debug, error, trace};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixStream;
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
 use tokio::process::Command;
 use tokio::time;
 
-use crate::{ExecBackend, UnixSocketBackend};
-
-fn strsig(sig: i32) -> &'static str {
-    match sig {
-        1 => "Hangup",
-        2 => "Interrupt",
-        3 => "Quit",
-        4 => "Illegal instruction",
-        5 => "Trace/breakpoint trap",
-        6 => "Aborted",
-        7 => "Bus error",
-        8 => "Floating point exception",
-        9 => "Killed",
-        10 => "User defined signal 1",
-        11 => "Segmentation fault",
-        12 => "User defined signal 2",
-        13 => "Broken pipe",
-        14 => "Alarm clock",
-        15 => "Terminated",
-        16 => "Stack fault",
-        17 => "Child exited",
-        18 => "Continued",
-        19 => "Stopped (signal)",
-        20 => "Stopped",
-        21 => "Stopped (tty input)",
-        22 => "Stopped (tty output)",
-        23 => "Urgent I/O condition",
-        24 => "CPU time limit exceeded",
-        25 => "File size limit exceeded",
-        26 => "Virtual timer expired",
-        27 => "Profiling timer expired",
-        28 => "Window changed",
-        29 => "I/O possible",
-        30 => "Power failure",
-        31 => "Bad system call",
-        _ => "Unknown signal",
-    }
-}
-
-fn strsigabbrev(sig: i32) -> &'static str {
-    match sig {
-        1 => "SIGHUP",
-        2 => "SIGINT",
-        3 => "SIGQUIT",
-        4 => "SIGILL",
-        5 => "SIGTRAP",
-        6 => "SIGABRT",
-        7 => "SIGBUS",
-        8 => "SIGFPE",
-        9 => "SIGKILL",
-        10 => "SIGUSR1",
-        11 => "SIGSEGV",
-        12 => "SIGUSR2",
-        13 => "SIGPIPE",
-        14 => "SIGALRM",
-        15 => "SIGTERM",
-        16 => "SIGSTKFLT",
-        17 => "SIGCHLD",
-        18 => "SIGCONT",
-        19 => "SIGSTOP",
-        20 => "SIGTSTP",
-        21 => "SIGTTIN",
-        22 => "SIGTTOU",
-        23 => "SIGURG",
-        24 => "SIGXCPU",
-        25 => "SIGXFSZ",
-        26 => "SIGVTALRM",
-        27 => "SIGPROF",
-        28 => "SIGWINCH",
-        29 => "SIGPOLL",
-        30 => "SIGPWR",
-        31 => "SIGSYS",
-        _ => "(unknown)",
-    }
-}
-
-pub async fn exec<'a, T>(
-    lang: Arc<ExecBackend>,
+use crate::cmdline::{Cmdline, Values};
+use crate::context::ContextInfo;
+use crate::daemon::Daemon;
+use crate::journal::Journal;
+use crate::monitor::{self, Monitor};
+use crate::output::{Output, OutputLimits};
+use crate::protocol::{self, Op};
+use crate::result::BadSyscall;
+use crate::sandbox::Sandbox;
+use crate::syscalls;
+use crate::trace;
+use crate::{EvalError, NetworkBackend, UnixSocketBackend};
+
+/// How long a program that timed out has to exit after `SIGTERM`, before it is killed.
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// What to run, after the code was wrapped in the language's template.
+pub struct Program<'a> {
+    pub code: &'a str,
+    /// Input for the program, as opposed to the code.
+    pub stdin: Option<&'a str>,
+    pub args: &'a [String],
+    pub env: &'a [(String, String)],
+    /// The name of the template the code was wrapped in.
+    pub variant: Option<&'a str>,
+}
+
+pub async fn exec(
+    cmdline: Arc<Cmdline>,
+    sandbox: Option<Arc<Sandbox>>,
     timeout: Option<usize>,
-    code: T,
-) -> Result<String, String>
-where
-    T: AsRef<[u8]> + 'a,
-{
-    let timeout_arg = format!(
-        "{}{}",
-        lang.timeout_prefix
-            .as_ref()
-            .map(String::as_str)
-            .unwrap_or(""),
-        timeout.unwrap_or(0)
-    );
-    if let Some(path) = lang.cmdline.iter().nth(0) {
+    context: Option<&str>,
+    program: &Program<'_>,
+    out: &mut Output,
+) -> Result<(), EvalError> {
+    // the workdir is removed when it goes out of scope, after the program has exited
+    let (args, input, _workdir) = prepare(&cmdline, timeout, context, program)?;
+    let input = input.to_owned();
+
+    if let Some((path, args)) = args.split_first() {
         let mut cmd = Command::new(path);
-        cmd.args(
-            lang.cmdline
-                .iter()
-                .skip(1)
-                .map(|a| if a == "{TIMEOUT}" { &timeout_arg } else { &a }),
-        )
-        .kill_on_drop(true)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+        // the sandbox clears the environment, so it goes first
+        let monitor = match sandbox {
+            Some(ref sandbox) => sandbox.apply(&mut cmd),
+            None => Monitor::apply(&mut cmd),
+        }
+        .map_err(EvalError::Io)?;
+        // the monitor leads a process group, so that whatever the program starts can be killed
+        cmd.args(args)
+            .envs(program.env.iter().map(|(k, v)| (k, v)))
+            .process_group(0)
+            .kill_on_drop(true)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
         debug!("spawning {:?}", cmd);
 
-        let mut child = cmd.spawn().map_err(|e| format!("failed to exec: {}", e))?;
-
-        {
-            let mut stdin = child
-                .stdin
-                .take()
-                .ok_or_else(|| "stdin missing".to_owned())?;
-            stdin
-                .write_all(code.as_ref())
-                .await
-                .map_err(|e| format!("failed to write to stdin: {}", e))?;
-            drop(stdin);
-        }
+        let mut child = cmd.spawn().map_err(EvalError::Spawn)?;
+        let (mut stdin, mut stdout, mut stderr) =
+            match (child.stdin.take(), child.stdout.take(), child.stderr.take()) {
+                (Some(stdin), Some(stdout), Some(stderr)) => (stdin, stdout, stderr),
+                _ => return Err(EvalError::Io(io::Error::other("child pipes missing"))),
+            };
+        let pgid = match child.id() {
+            Some(pid) => pid as libc::pid_t,
+            None => return Err(EvalError::Io(io::Error::other("child already reaped"))),
+        };
 
-        let output = child
-            .wait_with_output()
-            .await
-            .map_err(|e| format!("failed to wait for process: {}", e))?;
-        let mut r = format!(
-            "{}{}",
-            String::from_utf8_lossy(&output.stderr),
-            String::from_utf8_lossy(&output.stdout),
-        );
-        if !output.status.success() {
-            if !r.ends_with('\n') {
-                r.push_str("\n");
+        let write_stdin = async move {
+            match stdin.write_all(input.as_bytes()).await {
+                // the program doesn't have to read all of its input
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(EvalError::Io(e)),
+                _ => Ok(()),
             }
-            if let Some(code) = output.status.code() {
-                r.push_str(&format!("exited with status {}\n", code));
-            } else if let Some(code) = output.status.signal() {
-                r.push_str(&format!(
-                    "signalled with {} ({})\n",
-                    strsig(code),
-                    strsigabbrev(code)
-                ));
-            } else {
-                r.push_str("exited with unknown failure\n");
+        };
+        let run = async {
+            let read_output = async {
+                let mut outbuf = [0u8; 4096];
+                let mut errbuf = [0u8; 4096];
+                let (mut out_open, mut err_open) = (true, true);
+                while (out_open || err_open) && !out.is_full() {
+                    tokio::select! {
+                        n = stdout.read(&mut outbuf), if out_open => match n.map_err(EvalError::Io)? {
+                            0 => out_open = false,
+                            n => out.stdout(&outbuf[..n]),
+                        },
+                        n = stderr.read(&mut errbuf), if err_open => match n.map_err(EvalError::Io)? {
+                            0 => err_open = false,
+                            n => out.stderr(&errbuf[..n]),
+                        },
+                    }
+                }
+                if out.is_full() {
+                    debug!("output limit reached, killing child");
+                    monitor::signal_group(pgid, libc::SIGKILL);
+                }
+                Ok(())
+            };
+            let (written, read) = tokio::join!(write_stdin, read_output);
+            written?;
+            read?;
+            child.wait().await.map_err(EvalError::Io)
+        };
+        let status = match timeout {
+            Some(timeout) => match time::timeout(Duration::from_secs(timeout as u64), run).await {
+                Ok(status) => status?,
+                Err(_) => {
+                    debug!("timed out, terminating child");
+                    terminate(pgid).await;
+                    return Err(EvalError::Timeout);
+                }
+            },
+            None => run.await?,
+        };
+        let report = monitor.report();
+        let full = out.is_full();
+        let result = out.result_mut();
+        result.usage = report.usage;
+        // if we killed it ourselves, how it ended is not interesting
+        if !full {
+            result.exit_code = status.code();
+            result.signal = status.signal();
+            result.oom_killed = report.oom_killed;
+            if status.signal() == Some(libc::SIGSYS) {
+                result.bad_syscall = report.bad_syscall.map(|number| BadSyscall {
+                    number,
+                    name: syscalls::name(number),
+                });
             }
         }
-        Ok(r)
+        Ok(())
     } else {
-        Err("empty cmdline".to_owned())
+        Err(EvalError::Config("empty cmdline".to_owned()))
     }
 }
 
-pub async fn unix<'a, T, U>(
+/// Terminates the process group of a program that timed out, and kills it if the program has not
+/// exited after `KILL_GRACE`. The group's leader must not have been reaped yet.
+///
+/// A sandboxed program is the init of its PID namespace, which only gets `SIGTERM` from outside
+/// if it handles it, so most are only killed after the grace period. Its children do get it.
+async fn terminate(pgid: libc::pid_t) {
+    monitor::signal_group(pgid, libc::SIGTERM);
+    let deadline = time::Instant::now() + KILL_GRACE;
+    while !monitor::has_exited(pgid) && time::Instant::now() < deadline {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    // anything the program left behind goes too
+    monitor::signal_group(pgid, libc::SIGKILL);
+}
+
+/// Runs a program like `exec` does, but under ptrace, and returns the numbers of the syscalls it
+/// made and how it exited. Blocks until the program has exited.
+pub fn trace(
+    cmdline: &Cmdline,
+    sandbox: Option<&Sandbox>,
+    timeout: Option<usize>,
+    context: Option<&str>,
+    program: &Program<'_>,
+) -> Result<(BTreeSet<u32>, ExitStatus), EvalError> {
+    let (args, input, _workdir) = prepare(cmdline, timeout, context, program)?;
+    let limit = timeout.map(|timeout| Duration::from_secs(timeout as u64));
+    trace::trace(&args, program.env, input, sandbox, limit).map_err(|e| match e.kind() {
+        io::ErrorKind::TimedOut => EvalError::Timeout,
+        _ => EvalError::Io(e),
+    })
+}
+
+/// Returns the program's arguments, what to write to its stdin, and its `{WORKDIR}` if it has one.
+fn prepare<'a>(
+    cmdline: &Cmdline,
+    timeout: Option<usize>,
+    context: Option<&str>,
+    program: &Program<'a>,
+) -> Result<(Vec<String>, &'a str, Option<Workdir>), EvalError> {
+    // the code goes either in an argument or to stdin, leaving the other for the program
+    let input = if cmdline.has_code() {
+        program.stdin.unwrap_or("")
+    } else if program.stdin.is_some() {
+        return Err(EvalError::Unsupported(
+            "the code is read from stdin, so the program cannot have input".to_owned(),
+        ));
+    } else {
+        program.code
+    };
+    let workdir = if cmdline.has_workdir() {
+        Some(Workdir::create().map_err(EvalError::Io)?)
+    } else {
+        None
+    };
+    let args = cmdline.expand(&Values {
+        timeout,
+        context,
+        variant: program.variant,
+        workdir: workdir.as_ref().and_then(Workdir::path),
+        code: program.code,
+        args: program.args,
+    });
+    Ok((args, input, workdir))
+}
+
+/// A directory for `{WORKDIR}`, removed with everything in it when dropped.
+pub struct Workdir(PathBuf);
+
+impl Workdir {
+    pub fn create() -> io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = env::temp_dir().join(format!(
+            "evalbot.{}.{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&path)?;
+        Ok(Workdir(path))
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.0.to_str()
+    }
+}
+
+impl Drop for Workdir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            warn!("failed to remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+pub async fn unix(
     lang: Arc<UnixSocketBackend>,
+    daemon: Option<Arc<Daemon>>,
+    journal: Option<Arc<Journal>>,
+    timeout: Option<usize>,
+    context: Option<&str>,
+    program: &Program<'_>,
+    out: &mut Output,
+) -> Result<(), EvalError> {
+    let generation = match daemon {
+        Some(ref daemon) => Some(daemon.ready().await?),
+        None => None,
+    };
+    let connect = || async {
+        UnixStream::connect(&lang.socket_addr)
+            .await
+            .map_err(EvalError::Connect)
+    };
+    let res = session(
+        connect,
+        lang.protocol.unwrap_or(1),
+        journal.as_deref(),
+        generation,
+        timeout,
+        context,
+        program,
+        out,
+    )
+    .await;
+    if timed_out(&res) {
+        match (daemon, generation) {
+            (Some(daemon), Some(generation)) => daemon.restart(generation),
+            _ => persistent_timeout(&lang.timeout_cmdline, journal.as_deref()).await,
+        }
+    }
+    res
+}
+
+pub async fn network(
+    lang: Arc<NetworkBackend>,
+    journal: Option<Arc<Journal>>,
+    timeout: Option<usize>,
+    context: Option<&str>,
+    program: &Program<'_>,
+    out: &mut Output,
+) -> Result<(), EvalError> {
+    let connect = || async {
+        let conn = TcpStream::connect(&lang.network_addr)
+            .await
+            .map_err(EvalError::Connect)?;
+        conn.set_nodelay(true).map_err(EvalError::Connect)?;
+        Ok(conn)
+    };
+    let res = session(
+        connect,
+        lang.protocol.unwrap_or(1),
+        journal.as_deref(),
+        None,
+        timeout,
+        context,
+        program,
+        out,
+    )
+    .await;
+    if timed_out(&res) {
+        persistent_timeout(&lang.timeout_cmdline, journal.as_deref()).await;
+    }
+    res
+}
+
+/// Whether a persistent evaluator timed out, evaluating either the code or the context's journal,
+/// and so has to be killed.
+fn timed_out(res: &Result<(), EvalError>) -> bool {
+    match res {
+        Err(EvalError::Timeout) => true,
+        Err(EvalError::Replay(e)) => matches!(**e, EvalError::Timeout),
+        _ => false,
+    }
+}
+
+/// Evaluates `code` in a persistent evaluator, first replaying the context's journal if the
+/// daemon has lost the context since it was last used.
+#[allow(clippy::too_many_arguments)]
+async fn session<C, F, S>(
+    connect: C,
+    version: u32,
+    journal: Option<&Journal>,
+    generation: Option<u64>,
+    timeout: Option<usize>,
+    context: Option<&str>,
+    program: &Program<'_>,
+    out: &mut Output,
+) -> Result<(), EvalError>
+where
+    C: Fn() -> F,
+    F: Future<Output = Result<S, EvalError>>,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if !program.args.is_empty() || !program.env.is_empty() {
+        return Err(EvalError::Unsupported(
+            "persistent evaluators take no arguments or environment".to_owned(),
+        ));
+    }
+    let journal = match (journal, context) {
+        (Some(journal), Some(context)) => Some((journal, context, journal.epoch(generation))),
+        _ => None,
+    };
+    if let Some((journal, context, epoch)) = journal {
+        // concurrent evaluations in the context wait for the replay instead of repeating it
+        let _replay = journal.replay_lock(context).await;
+        let inputs = journal.stale(context, epoch);
+        if !inputs.is_empty() {
+            info!("replaying {} inputs into context {}", inputs.len(), context);
+        }
+        // the whole replay gets the time one evaluation would
+        let replay = until(deadline(timeout), async {
+            for input in inputs {
+                let mut discard = Output::new(None, OutputLimits::default());
+                persistent(
+                    connect(),
+                    version,
+                    timeout,
+                    Some(context),
+                    &input.code,
+                    input.stdin.as_deref(),
+                    &mut discard,
+                )
+                .await?;
+            }
+            Ok(())
+        });
+        if let Err(e) = replay.await {
+            warn!(
+                "replaying context {} failed, giving up on it: {}",
+                context, e
+            );
+            // the next evaluation starts over in an empty context
+            journal.clear(context);
+            return Err(EvalError::Replay(Box::new(e)));
+        }
+    }
+
+    let res = persistent(
+        connect(),
+        version,
+        timeout,
+        context,
+        program.code,
+        program.stdin,
+        out,
+    )
+    .await;
+    if let (Ok(()), Some((journal, context, epoch))) = (&res, journal) {
+        journal.record(context, epoch, program.code, program.stdin);
+    }
+    res
+}
+
+/// Connects with `connect`, sends one request and reads the response, all within the timeout.
+/// Killing a daemon that timed out is left to the caller.
+pub async fn persistent<F, S, T, U>(
+    connect: F,
+    version: u32,
     timeout: Option<usize>,
     context: Option<U>,
     code: T,
-) -> Result<String, String>
+    stdin: Option<&str>,
+    out: &mut Output,
+) -> Result<(), EvalError>
 where
+    F: Future<Output = Result<S, EvalError>>,
+    S: AsyncRead + AsyncWrite + Unpin,
     T: AsRef<[u8]>,
     U: AsRef<[u8]>,
 {
-    let buf = make_persistent_input(timeout, context, code);
-
-    let mut conn = UnixStream::connect(&lang.socket_addr)
-        .await
-        .map_err(|e| format!("error connecting: {}", e))?;
-    conn.write_all(&buf)
-        .await
-        .map_err(|e| format!("error writing: {}", e))?;
-    conn.flush()
-        .await
-        .map_err(|e| format!("error flushing: {}", e))?;
-
-    let mut lenb = [0u8; 4];
-    if let Some(timeout) = timeout {
-        if let Ok(res) = time::timeout(
-            Duration::from_secs(timeout as u64),
-            conn.read_exact(&mut lenb),
-        )
-        .await
-        {
-            res
-        } else {
-            drop(do_persistent_timeout(&lang.timeout_cmdline).await);
-            return Err("time limit exceeded".to_owned());
+    protocol::check_version(version)?;
+    let buf = if version == 1 {
+        if stdin.is_some() {
+            return Err(EvalError::Unsupported(
+                "giving the program input needs protocol v2".to_owned(),
+            ));
         }
+        protocol::make_v1_request(timeout, context, code)
     } else {
-        conn.read_exact(&mut lenb).await
+        protocol::make_v2_request(timeout, context, code, stdin)
+    };
+
+    let deadline = deadline(timeout);
+    let mut conn = connect_until(deadline, connect).await?;
+    until(deadline, async {
+        conn.write_all(&buf).await.map_err(EvalError::Io)?;
+        conn.flush().await.map_err(EvalError::Io)?;
+        if version == 1 {
+            protocol::read_v1_response(&mut conn, out).await
+        } else {
+            protocol::read_v2_response(&mut conn, out).await
+        }
+    })
+    .await
+}
+
+pub async fn unix_op(
+    lang: Arc<UnixSocketBackend>,
+    daemon: Option<Arc<Daemon>>,
+    timeout: Option<usize>,
+    op: Op,
+    context: Option<&str>,
+) -> Result<Vec<ContextInfo>, EvalError> {
+    if let Some(ref daemon) = daemon {
+        daemon.ready().await?;
+    }
+    let connect = async {
+        UnixStream::connect(&lang.socket_addr)
+            .await
+            .map_err(EvalError::Connect)
+    };
+    let version = lang.protocol.unwrap_or(1);
+    persistent_op(connect, version, lang.context_ops, timeout, op, context).await
+}
+
+pub async fn network_op(
+    lang: Arc<NetworkBackend>,
+    timeout: Option<usize>,
+    op: Op,
+    context: Option<&str>,
+) -> Result<Vec<ContextInfo>, EvalError> {
+    let connect = async {
+        let conn = TcpStream::connect(&lang.network_addr)
+            .await
+            .map_err(EvalError::Connect)?;
+        conn.set_nodelay(true).map_err(EvalError::Connect)?;
+        Ok(conn)
+    };
+    let version = lang.protocol.unwrap_or(1);
+    persistent_op(connect, version, lang.context_ops, timeout, op, context).await
+}
+
+async fn persistent_op<F, S>(
+    connect: F,
+    version: u32,
+    context_ops: Option<bool>,
+    timeout: Option<usize>,
+    op: Op,
+    context: Option<&str>,
+) -> Result<Vec<ContextInfo>, EvalError>
+where
+    F: Future<Output = Result<S, EvalError>>,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    protocol::check_version(version)?;
+    if version < 2 {
+        return Err(EvalError::Unsupported(
+            "managing contexts needs protocol v2".to_owned(),
+        ));
+    }
+    if context_ops != Some(true) {
+        return Err(EvalError::Unsupported(
+            "the evaluator is not configured to manage contexts".to_owned(),
+        ));
     }
-    .map_err(|e| format!("error reading result length: {}", e))?;
+    let buf = protocol::make_v2_op_request(op, context);
+    let deadline = deadline(timeout);
+    let mut conn = connect_until(deadline, connect).await?;
+    until(deadline, async {
+        conn.write_all(&buf).await.map_err(EvalError::Io)?;
+        conn.flush().await.map_err(EvalError::Io)?;
+        protocol::read_v2_op_response(&mut conn).await
+    })
+    .await
+}
 
-    let outlen = Cursor::new(lenb).get_u32_le().min(1024) as usize;
-    let mut buf = BytesMut::with_capacity(outlen);
-    trace!("result length: {}", outlen);
-    buf.resize(outlen, 0);
-    conn.read_exact(&mut buf)
-        .await
-        .map_err(|e| format!("error reading result: {}", e))?;
+/// When a request with a timeout of `timeout` seconds, starting now, has to be done.
+fn deadline(timeout: Option<usize>) -> Option<time::Instant> {
+    timeout.map(|timeout| time::Instant::now() + Duration::from_secs(timeout as u64))
+}
 
-    trace!("result: {:?}", buf);
-    Ok(String::from_utf8_lossy(&buf).into_owned())
+/// Runs `f`, failing with `EvalError::Timeout` if it is not done by `deadline`.
+async fn until<F, T>(deadline: Option<time::Instant>, f: F) -> Result<T, EvalError>
+where
+    F: Future<Output = Result<T, EvalError>>,
+{
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, f)
+            .await
+            .unwrap_or(Err(EvalError::Timeout)),
+        None => f.await,
+    }
 }
 
+/// Connects with `connect` by `deadline`. Running out of time connecting is a connection error,
+/// since the evaluator never got the request.
+async fn connect_until<F, S>(deadline: Option<time::Instant>, connect: F) -> Result<S, EvalError>
+where
+    F: Future<Output = Result<S, EvalError>>,
+{
+    match until(deadline, connect).await {
+        Err(EvalError::Timeout) => Err(EvalError::Connect(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timed out connecting",
+        ))),
+        res => res,
+    }
+}
+
+async fn persistent_timeout(cmdline: &Option<Vec<String>>, journal: Option<&Journal>) {
+    if do_persistent_timeout(cmdline).await.is_ok() && cmdline.is_some() {
+        if let Some(journal) = journal {
+            journal.killed();
+        }
+    }
+}
+
+#[allow(clippy::iter_nth, clippy::iter_nth_zero)]
 async fn do_persistent_timeout(cmdline: &Option<Vec<String>>) -> Result<(), ()> {
     if let Some(cmdline) = cmdline.as_ref() {
         if let Some(path) = cmdline.iter().nth(0) {
@@ -230,26 +574,3 @@ async fn do_persistent_timeout(cmdline: &Option<Vec<String>>) -> Result<(), ()>
 
     Ok(())
 }
-
-fn make_persistent_input<T, U>(timeout: Option<usize>, context: Option<T>, code: U) -> BytesMut
-where
-    T: AsRef<[u8]>,
-    U: AsRef<[u8]>,
-{
-    let timeout = timeout.unwrap_or(0usize) as u32;
-    let contextb = context
-        .as_ref()
-        .map(|x| x.as_ref())
-        .unwrap_or(&super::EMPTY_U8);
-    let codeb = code.as_ref();
-    let contextblen = contextb.len() as u32;
-    let codeblen = codeb.len() as u32;
-
-    let mut buf = BytesMut::with_capacity(12usize + contextblen as usize + codeblen as usize);
-    buf.put_u32_le(timeout * 1000);
-    buf.put_u32_le(contextblen);
-    buf.put_u32_le(codeblen);
-    buf.put(&contextb[..contextblen as usize]);
-    buf.put(&codeb[..codeblen as usize]);
-    buf
-}
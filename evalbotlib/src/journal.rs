@@ -0,0 +1,215 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::OwnedMutexGuard;
+
+/// The inputs evaluated in each context of a persistent evaluator, so that the contexts can be
+/// rebuilt after the daemon is restarted.
+///
+/// Each context remembers the epoch its state was built in. The epoch changes whenever the daemon
+/// loses its state: for a supervised daemon it is the daemon's generation, otherwise the number of
+/// times the daemon was killed after a timeout.
+#[derive(Debug)]
+pub struct Journal {
+    max_inputs: usize,
+    state: Mutex<JournalState>,
+}
+
+#[derive(Debug, Default)]
+struct JournalState {
+    kills: u64,
+    contexts: HashMap<String, Entry>,
+    /// Held while a context is being replayed, so that it is only replayed once.
+    replays: HashMap<String, Arc<tokio::sync::Mutex<()>>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    epoch: u64,
+    inputs: VecDeque<Input>,
+}
+
+/// Keeps other evaluations in a context from replaying it, released on drop.
+#[derive(Debug)]
+pub struct ReplayGuard<'a> {
+    journal: &'a Journal,
+    context: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+/// An input evaluated in a context: the code, and what it read from stdin.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Input {
+    pub code: String,
+    pub stdin: Option<String>,
+}
+
+impl Journal {
+    /// Returns `prev` if it keeps as many inputs, so that the journal survives a reload.
+    pub fn reuse(prev: Option<&Arc<Journal>>, max_inputs: Option<usize>) -> Option<Arc<Self>> {
+        let max_inputs = max_inputs.filter(|&n| n != 0)?;
+        match prev {
+            Some(prev) if prev.max_inputs == max_inputs => Some(prev.clone()),
+            _ => Some(Arc::new(Journal {
+                max_inputs,
+                state: Mutex::new(JournalState::default()),
+            })),
+        }
+    }
+
+    /// The current epoch, given the generation of the supervised daemon, if any.
+    pub fn epoch(&self, generation: Option<u64>) -> u64 {
+        generation.unwrap_or_else(|| self.lock().kills)
+    }
+
+    /// Notes that the daemon was killed, and so has lost all of its contexts.
+    pub fn killed(&self) {
+        self.lock().kills += 1;
+    }
+
+    /// Waits until no other evaluation is replaying `context`. Until the guard is dropped, others
+    /// wait in turn, and then find the context current.
+    pub async fn replay_lock(&self, context: &str) -> ReplayGuard<'_> {
+        let lock = self
+            .lock()
+            .replays
+            .entry(context.to_owned())
+            .or_default()
+            .clone();
+        ReplayGuard {
+            journal: self,
+            context: context.to_owned(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+
+    /// Returns the inputs to replay if `context` was built before `epoch`, and marks it as
+    /// current.
+    pub fn stale(&self, context: &str, epoch: u64) -> Vec<Input> {
+        match self.lock().contexts.get_mut(context) {
+            Some(entry) if entry.epoch != epoch => {
+                entry.epoch = epoch;
+                entry.inputs.iter().cloned().collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Adds an input that was evaluated successfully in `epoch`, forgetting the oldest one if
+    /// the journal is full.
+    pub fn record(&self, context: &str, epoch: u64, code: &str, stdin: Option<&str>) {
+        let mut state = self.lock();
+        let entry = state
+            .contexts
+            .entry(context.to_owned())
+            .or_insert_with(|| Entry {
+                epoch,
+                inputs: VecDeque::new(),
+            });
+        if epoch < entry.epoch {
+            // it ran in a daemon that has since been replaced, so its effects are gone
+            return;
+        }
+        entry.epoch = epoch;
+        if entry.inputs.len() >= self.max_inputs {
+            entry.inputs.pop_front();
+        }
+        entry.inputs.push_back(Input {
+            code: code.to_owned(),
+            stdin: stdin.map(str::to_owned),
+        });
+    }
+
+    pub fn clear(&self, context: &str) {
+        self.lock().contexts.remove(context);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JournalState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for ReplayGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.journal.lock();
+        self.guard.take();
+        // forget the lock once nobody else is waiting for it
+        if let Some(lock) = state.replays.get(&self.context) {
+            if Arc::strong_count(lock) == 1 {
+                state.replays.remove(&self.context);
+            }
+        }
+    }
+}
+
+impl PartialEq for Journal {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_inputs == other.max_inputs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::FutureExt;
+
+    use super::{Input, Journal};
+
+    fn code(journal: &Journal, context: &str, epoch: u64) -> Vec<String> {
+        journal
+            .stale(context, epoch)
+            .into_iter()
+            .map(|input| input.code)
+            .collect()
+    }
+
+    #[test]
+    fn test_journal() {
+        let journal = Journal::reuse(None, Some(2)).unwrap();
+        let epoch = journal.epoch(None);
+        for input in &["a = 1", "b = 2", "c = 3"] {
+            assert!(journal.stale("ctx", epoch).is_empty());
+            journal.record("ctx", epoch, input, None);
+        }
+
+        journal.killed();
+        let epoch = journal.epoch(None);
+        assert_eq!(code(&journal, "ctx", epoch), vec!["b = 2", "c = 3"]);
+        // replayed once, it is current again
+        assert!(journal.stale("ctx", epoch).is_empty());
+        // an input that ran in the old daemon is gone with it
+        journal.record("ctx", epoch - 1, "d = 4", None);
+        journal.killed();
+        assert_eq!(
+            code(&journal, "ctx", journal.epoch(None)),
+            vec!["b = 2", "c = 3"]
+        );
+
+        // input read from stdin is replayed along with the code
+        let epoch = journal.epoch(None);
+        journal.record("ctx", epoch, "e = input()", Some("5"));
+        journal.killed();
+        assert_eq!(
+            journal.stale("ctx", journal.epoch(None)).last(),
+            Some(&Input {
+                code: "e = input()".to_owned(),
+                stdin: Some("5".to_owned()),
+            })
+        );
+
+        journal.clear("ctx");
+        assert!(journal.stale("ctx", journal.epoch(Some(5))).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replay_lock() {
+        let journal = Journal::reuse(None, Some(2)).unwrap();
+        let guard = journal.replay_lock("ctx").await;
+        // other contexts are not held up
+        assert!(journal.replay_lock("other").now_or_never().is_some());
+        let mut waiting = Box::pin(journal.replay_lock("ctx"));
+        assert!((&mut waiting).now_or_never().is_none());
+        drop(guard);
+        assert!(waiting.now_or_never().is_some());
+        assert!(journal.lock().replays.is_empty());
+    }
+}
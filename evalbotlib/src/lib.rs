@@ -1,180 +1,895 @@
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::path::Path;
-use std::sync::Arc;
+use std::process::ExitStatus;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
 
-use log::debug;
+use futures::channel::mpsc;
+use futures::{future, stream, Stream, StreamExt};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
+use toml::value::{Table, Value};
 
+use cmdline::Cmdline;
+use context::Contexts;
+use daemon::Daemon;
+use eval::Program;
+use journal::Journal;
+use limit::{Limiter, Permit};
+use output::{Output, OutputLimits};
+use protocol::Op;
+use sandbox::Sandbox;
+use template::{Template, WrapRule};
+
+mod cmdline;
+mod context;
+mod daemon;
+mod error;
 mod eval;
+mod journal;
+mod limit;
+mod monitor;
+mod output;
+mod preset;
+mod protocol;
+mod request;
+mod result;
+mod sandbox;
+mod syscalls;
+mod template;
+mod trace;
 pub mod util;
+mod validate;
+
+pub use context::ContextInfo;
+pub use error::EvalError;
+pub use request::{EvalRequest, TimeLimit};
+pub use result::{BadSyscall, EvalEvent, EvalResult, ResourceUsage};
+pub use syscalls::{ListProblem, SyscallList};
+pub use validate::{ConfigProblem, Severity};
+
+/// Output beyond this many bytes is discarded unless configured otherwise.
+const DEFAULT_MAX_OUTPUT_BYTES: usize = 64 * 1024;
 
 #[derive(Clone, Serialize, Deserialize, Default, PartialEq, Debug)]
 struct EvalServiceCfg {
     timeout: usize,
-    languages: HashMap<String, LanguageCfg>,
+    max_output_bytes: Option<usize>,
+    max_output_lines: Option<usize>,
+    max_concurrent: Option<usize>,
+    max_queue: Option<usize>,
+    #[serde(default)]
+    presets: Table,
+    /// Each language's table, until the presets it extends are merged in.
+    languages: HashMap<String, Table>,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 struct LanguageCfg {
+    #[serde(default)]
+    aliases: Vec<String>,
+    display_name: Option<String>,
+    description: Option<String>,
+    extension: Option<String>,
+    highlight: Option<String>,
     code_before: Option<String>,
     code_after: Option<String>,
+    #[serde(default)]
+    templates: HashMap<String, String>,
+    default_template: Option<String>,
+    #[serde(default)]
+    wrap_rules: Vec<WrapRuleCfg>,
+    #[serde(default)]
+    vars: HashMap<String, String>,
     timeout: Option<usize>,
+    max_output_bytes: Option<usize>,
+    max_output_lines: Option<usize>,
+    max_concurrent: Option<usize>,
+    max_queue: Option<usize>,
+    context_ttl: Option<u64>,
+    journal_size: Option<usize>,
     #[serde(flatten)]
     backend: BackendCfg,
 }
 
+/// Code matching `matches` is wrapped in `template` instead of the default one, or not wrapped at
+/// all if `template` is not given. The first rule that matches applies.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+struct WrapRuleCfg {
+    matches: String,
+    template: Option<String>,
+}
+
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 #[serde(untagged)]
 enum BackendCfg {
+    Sandbox(SandboxBackend),
     Exec(ExecBackend),
     Network(NetworkBackend),
     UnixSocket(UnixSocketBackend),
 }
 
-#[derive(Clone, Debug)]
+impl BackendCfg {
+    fn context_ops(&self) -> bool {
+        match self {
+            BackendCfg::Exec(_) | BackendCfg::Sandbox(_) => false,
+            BackendCfg::Network(x) => x.context_ops.unwrap_or(false),
+            BackendCfg::UnixSocket(x) => x.context_ops.unwrap_or(false),
+        }
+    }
+}
+
+/// The set of configured languages.
+///
+/// The configuration can be replaced at runtime with `reload`. Evaluations that are already running
+/// keep using the `Language` they started with.
+#[derive(Debug)]
 pub struct EvalService {
+    state: RwLock<Arc<EvalServiceState>>,
+    path: Option<String>,
+}
+
+#[derive(Debug)]
+struct EvalServiceState {
     timeout: usize,
+    limiter: Option<Arc<Limiter>>,
     languages: HashMap<String, Arc<Language>>,
+    /// Maps each alias to the name of its language.
+    aliases: HashMap<String, String>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Language {
     name: String,
-    code_before: Option<String>,
-    code_after: Option<String>,
+    aliases: Vec<String>,
+    display_name: Option<String>,
+    description: Option<String>,
+    extension: Option<String>,
+    highlight: Option<String>,
+    templates: HashMap<String, Template>,
+    default_template: Template,
+    /// The name of the default template, if it has one.
+    default_variant: Option<String>,
+    wrap_rules: Vec<WrapRule>,
+    vars: HashMap<String, String>,
     timeout: Option<usize>,
+    output_limits: OutputLimits,
+    limiter: Option<Arc<Limiter>>,
+    global_limiter: Option<Arc<Limiter>>,
+    contexts: Option<Arc<Contexts>>,
+    journal: Option<Arc<Journal>>,
     backend: Backend,
 }
 
+/// How a language's code is evaluated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BackendKind {
+    /// A new process for each evaluation.
+    Exec,
+    /// A new process for each evaluation, in a sandbox set up by the bot.
+    Sandbox,
+    /// A persistent evaluator listening on a TCP socket.
+    Network,
+    /// A persistent evaluator listening on a Unix socket.
+    UnixSocket,
+}
+
 #[derive(Clone, PartialEq, Debug)]
 enum Backend {
-    Exec(Arc<ExecBackend>),
+    Exec(Arc<Cmdline>),
+    Sandbox(Arc<Cmdline>, Arc<Sandbox>),
     Network(Arc<NetworkBackend>),
-    UnixSocket(Arc<UnixSocketBackend>),
+    UnixSocket(Arc<UnixSocketBackend>, Option<Arc<Daemon>>),
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct ExecBackend {
     cmdline: Vec<String>,
     timeout_prefix: Option<String>,
+    /// For `{MEMORY}` in `cmdline`.
+    memory_limit: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct SandboxBackend {
+    cmdline: Vec<String>,
+    timeout_prefix: Option<String>,
+    sandbox: SandboxCfg,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct NetworkBackend {
     network_addr: String,
     timeout_cmdline: Option<Vec<String>>,
+    protocol: Option<u32>,
+    /// Whether the evaluator takes ops other than Eval, which older ones would evaluate instead.
+    context_ops: Option<bool>,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct UnixSocketBackend {
     socket_addr: String,
     timeout_cmdline: Option<Vec<String>>,
+    protocol: Option<u32>,
+    context_ops: Option<bool>,
+    daemon: Option<DaemonCfg>,
+}
+
+/// An evaluator daemon to run on the socket instead of relying on a systemd socket unit.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct DaemonCfg {
+    cmdline: Vec<String>,
+    restart_delay: Option<u64>,
+    start_timeout: Option<u64>,
+}
+
+/// How to sandbox programs run by `cmdline`. Sizes are in MiB.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct SandboxCfg {
+    /// The directory to use as `/`, mounted read-only.
+    root: String,
+    /// A file listing the syscalls the program may make, one name per line.
+    syscalls: Option<String>,
+    hostname: Option<String>,
+    /// The user and group ID the program runs as, which are mapped to the bot's own.
+    uid: Option<u32>,
+    /// Devices to make available in `/dev`.
+    devices: Option<Vec<String>>,
+    /// Whether to share the host's network instead of having none.
+    network: Option<bool>,
+    tmp_size: Option<u64>,
+    /// A cgroup v2 directory to create a cgroup for each program in, to apply the limits below.
+    cgroup: Option<String>,
+    memory_limit: Option<u64>,
+    pids_limit: Option<u64>,
+    /// How many CPUs worth of time the program may use.
+    cpu_limit: Option<f64>,
 }
 
 impl Language {
-    fn from(name: String, default_timeout: usize, cfg: LanguageCfg) -> Self {
-        Language {
+    fn from(
+        name: String,
+        service: &EvalServiceCfg,
+        cfg: LanguageCfg,
+        global_limiter: Option<Arc<Limiter>>,
+        prev: Option<&Language>,
+    ) -> Result<Self, EvalError> {
+        let mut templates = HashMap::new();
+        for (template_name, template) in cfg.templates {
+            let parsed = Template::parse(&template, &cfg.vars).map_err(|e| {
+                EvalError::Config(format!(
+                    "language {}: template {}: {}",
+                    name, template_name, e
+                ))
+            })?;
+            templates.insert(template_name, parsed);
+        }
+        let default_template = match cfg.default_template {
+            Some(ref default) => templates.get(default).cloned().ok_or_else(|| {
+                EvalError::Config(format!(
+                    "language {}: default_template {} is not defined",
+                    name, default
+                ))
+            })?,
+            None => Template::around(cfg.code_before.as_deref(), cfg.code_after.as_deref()),
+        };
+        let mut wrap_rules = Vec::new();
+        for (i, rule) in cfg.wrap_rules.into_iter().enumerate() {
+            if let Some(ref template) = rule.template {
+                if !templates.contains_key(template) {
+                    return Err(EvalError::Config(format!(
+                        "language {}: wrap rule {}: template {} is not defined",
+                        name, i, template
+                    )));
+                }
+            }
+            wrap_rules.push(WrapRule::new(&rule.matches, rule.template).map_err(|e| {
+                EvalError::Config(format!("language {}: wrap rule {}: {}", name, i, e))
+            })?);
+        }
+        // contexts are only dropped when they expire if the evaluator can be told to
+        let context_ops = cfg.backend.context_ops();
+        let context_ttl = cfg.context_ttl.filter(|_| context_ops);
+        let backend = match cfg.backend {
+            BackendCfg::Exec(x) => Backend::Exec(Arc::new(
+                Cmdline::parse(
+                    &x.cmdline,
+                    &name,
+                    x.timeout_prefix.as_deref(),
+                    x.memory_limit,
+                    &cfg.vars,
+                )
+                .map_err(|e| EvalError::Config(format!("language {}: cmdline: {}", name, e)))?,
+            )),
+            BackendCfg::Sandbox(x) => {
+                let cmdline = Cmdline::parse(
+                    &x.cmdline,
+                    &name,
+                    x.timeout_prefix.as_deref(),
+                    x.sandbox.memory_limit,
+                    &cfg.vars,
+                )
+                .map_err(|e| EvalError::Config(format!("language {}: cmdline: {}", name, e)))?;
+                if cmdline.has_workdir() {
+                    return Err(EvalError::Config(format!(
+                        "language {}: cmdline: {{WORKDIR}} is not mounted in the sandbox; use /tmp",
+                        name
+                    )));
+                }
+                let sandbox = Sandbox::new(x.sandbox)
+                    .map_err(|e| EvalError::Config(format!("language {}: sandbox: {}", name, e)))?;
+                Backend::Sandbox(Arc::new(cmdline), Arc::new(sandbox))
+            }
+            BackendCfg::Network(x) => Backend::Network(Arc::new(x)),
+            BackendCfg::UnixSocket(x) => {
+                let prev_daemon = prev.and_then(|l| match l.backend {
+                    Backend::UnixSocket(_, ref daemon) => daemon.as_ref(),
+                    _ => None,
+                });
+                let daemon = Daemon::reuse(prev_daemon, x.daemon.as_ref(), &x.socket_addr);
+                Backend::UnixSocket(Arc::new(x), daemon)
+            }
+        };
+        Ok(Language {
             name,
-            code_before: cfg.code_before,
-            code_after: cfg.code_after,
-            timeout: cfg.timeout.or_else(|| Some(default_timeout)),
-            backend: match cfg.backend {
-                BackendCfg::Exec(x) => Backend::Exec(Arc::new(x)),
-                BackendCfg::Network(x) => Backend::Network(Arc::new(x)),
-                BackendCfg::UnixSocket(x) => Backend::UnixSocket(Arc::new(x)),
+            aliases: cfg.aliases,
+            display_name: cfg.display_name,
+            description: cfg.description,
+            extension: cfg.extension,
+            highlight: cfg.highlight,
+            templates,
+            default_template,
+            default_variant: cfg.default_template,
+            wrap_rules,
+            vars: cfg.vars,
+            timeout: cfg.timeout.or(Some(service.timeout)),
+            output_limits: OutputLimits {
+                bytes: cfg
+                    .max_output_bytes
+                    .or(service.max_output_bytes)
+                    .or(Some(DEFAULT_MAX_OUTPUT_BYTES))
+                    .filter(|&n| n != 0),
+                lines: cfg
+                    .max_output_lines
+                    .or(service.max_output_lines)
+                    .filter(|&n| n != 0),
             },
-        }
+            limiter: Limiter::reuse(
+                prev.and_then(|l| l.limiter.as_ref()),
+                cfg.max_concurrent,
+                cfg.max_queue,
+            ),
+            global_limiter,
+            contexts: Contexts::reuse(prev.and_then(|l| l.contexts.as_ref()), context_ttl),
+            journal: Journal::reuse(prev.and_then(|l| l.journal.as_ref()), cfg.journal_size),
+            backend,
+        })
     }
 }
 
 impl EvalService {
-    fn fixup(cfg: EvalServiceCfg) -> Self {
+    fn fixup(
+        mut cfg: EvalServiceCfg,
+        prev: Option<&EvalServiceState>,
+    ) -> Result<EvalServiceState, EvalError> {
         debug!("Loaded config: {:#?}", cfg);
-        let mut new = EvalService {
+        let mut new = EvalServiceState {
             timeout: cfg.timeout,
+            limiter: Limiter::reuse(
+                prev.and_then(|p| p.limiter.as_ref()),
+                cfg.max_concurrent,
+                cfg.max_queue,
+            ),
             languages: HashMap::new(),
+            aliases: HashMap::new(),
         };
-        let timeout = cfg.timeout;
-        for (name, lang) in cfg.languages.into_iter() {
-            new.languages
-                .insert(name.clone(), Arc::new(Language::from(name, timeout, lang)));
+        for (name, lang) in std::mem::take(&mut cfg.languages) {
+            let lang = preset::resolve(&lang, &cfg.presets)
+                .and_then(|lang| {
+                    Value::Table(lang)
+                        .try_into::<LanguageCfg>()
+                        .map_err(|e| e.to_string())
+                })
+                .map_err(|e| EvalError::Config(format!("language {}: {}", name, e)))?;
+            let prev_lang = prev.and_then(|p| p.languages.get(&name));
+            let lang = Language::from(
+                name.clone(),
+                &cfg,
+                lang,
+                new.limiter.clone(),
+                prev_lang.map(|l| &**l),
+            )?;
+            new.languages.insert(name, Arc::new(lang));
+        }
+        for (name, lang) in &new.languages {
+            for alias in &lang.aliases {
+                if new.languages.contains_key(alias) {
+                    return Err(EvalError::Config(format!(
+                        "language {}: alias {} is the name of a language",
+                        name, alias
+                    )));
+                }
+                if let Some(other) = new.aliases.insert(alias.clone(), name.clone()) {
+                    return Err(EvalError::Config(format!(
+                        "languages {} and {} both have the alias {}",
+                        other, name, alias
+                    )));
+                }
+            }
+        }
+        Ok(new)
+    }
+
+    fn new(state: EvalServiceState, path: Option<String>) -> Self {
+        EvalService {
+            state: RwLock::new(Arc::new(state)),
+            path,
         }
-        new
     }
 
-    pub async fn from_toml_file<P>(path: P) -> Result<Self, String>
+    pub async fn from_toml_file<P>(path: P) -> Result<Self, EvalError>
     where
         P: AsRef<Path> + Send + Display + 'static,
     {
-        Ok(EvalService::fixup(util::decode(path).await?))
+        let path_str = path.to_string();
+        let toml = tokio::fs::read_to_string(path)
+            .await
+            .map_err(EvalError::Io)?;
+        Ok(EvalService::new(
+            EvalService::parse_toml(&toml, None)?,
+            Some(path_str),
+        ))
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self, EvalError> {
+        Ok(EvalService::new(EvalService::parse_toml(toml, None)?, None))
+    }
+
+    fn parse_toml(
+        toml: &str,
+        prev: Option<&EvalServiceState>,
+    ) -> Result<EvalServiceState, EvalError> {
+        // serde's errors for untagged enums are not very helpful, so try to explain
+        let explain = |message: String| {
+            let problems = EvalService::validate(toml)
+                .into_iter()
+                .filter(|p| p.severity == Severity::Error)
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>();
+            if problems.is_empty() {
+                EvalError::Config(message)
+            } else {
+                EvalError::Config(problems.join("; "))
+            }
+        };
+        let cfg =
+            toml::from_str(toml).map_err(|x| explain(format!("could not parse TOML: {}", x)))?;
+        EvalService::fixup(cfg, prev).map_err(|e| match e {
+            EvalError::Config(message) => explain(message),
+            e => e,
+        })
+    }
+
+    /// Checks a configuration for problems, including ones that would not prevent it from
+    /// loading, like missing executables.
+    pub fn validate(toml: &str) -> Vec<ConfigProblem> {
+        validate::validate(toml)
+    }
+
+    /// Re-reads the file the service was loaded from. If it cannot be loaded, the current
+    /// configuration is kept.
+    pub async fn reload(&self) -> Result<(), EvalError> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| EvalError::Config("service was not loaded from a file".to_owned()))?;
+        let toml = tokio::fs::read_to_string(path)
+            .await
+            .map_err(EvalError::Io)?;
+        let state = EvalService::parse_toml(&toml, Some(&self.state()))?;
+        self.replace(state);
+        Ok(())
+    }
+
+    /// Replaces the configuration with `toml`. If it cannot be parsed, the current configuration
+    /// is kept.
+    pub fn reload_from_toml(&self, toml: &str) -> Result<(), EvalError> {
+        let state = EvalService::parse_toml(toml, Some(&self.state()))?;
+        self.replace(state);
+        Ok(())
+    }
+
+    /// Reloads the configuration file whenever its modification time changes, checking every
+    /// `interval`. Never returns.
+    pub async fn watch(&self, interval: Duration) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => {
+                warn!("service was not loaded from a file, not watching");
+                return future::pending().await;
+            }
+        };
+        let mtime = || async {
+            tokio::fs::metadata(path)
+                .await
+                .and_then(|m| m.modified())
+                .ok()
+        };
+        let mut last: Option<SystemTime> = mtime().await;
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = mtime().await;
+            if current.is_some() && current != last {
+                last = current;
+                match self.reload().await {
+                    Ok(()) => info!("{} changed, reloaded", path),
+                    Err(e) => warn!("{} changed, but could not reload: {}", path, e),
+                }
+            }
+        }
+    }
+
+    fn replace(&self, state: EvalServiceState) {
+        *self.state.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(state);
+    }
+
+    fn state(&self) -> Arc<EvalServiceState> {
+        self.state
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn timeout(&self) -> usize {
+        self.state().timeout
     }
 
-    pub fn from_toml(toml: &str) -> Result<Self, String> {
-        toml::from_str(toml)
-            .map(EvalService::fixup)
-            .map_err(|x| format!("could not parse TOML: {:?}", x))
+    /// All configured languages, sorted by name.
+    pub fn langs(&self) -> Vec<(String, Arc<Language>)> {
+        let mut langs = self
+            .state()
+            .languages
+            .iter()
+            .map(|(n, l)| (n.clone(), l.clone()))
+            .collect::<Vec<_>>();
+        langs.sort_by(|a, b| a.0.cmp(&b.0));
+        langs
     }
 
-    pub fn langs(&self) -> impl Iterator<Item = (&str, &Arc<Language>)> {
-        self.languages.iter().map(|(n, l)| (n.as_str(), l))
+    /// Returns the language with the given name or alias.
+    pub fn get(&self, lang: &str) -> Option<Arc<Language>> {
+        let state = self.state();
+        let name = state.aliases.get(lang).map_or(lang, String::as_str);
+        state.languages.get(name).cloned()
     }
 
-    pub fn get(&self, lang: &str) -> Option<&Arc<Language>> {
-        self.languages.get(lang)
+    pub fn lookup(&self, lang: &str) -> Result<Arc<Language>, EvalError> {
+        self.get(lang)
+            .ok_or_else(|| EvalError::UnknownLanguage(lang.to_owned()))
     }
 }
 
 static EMPTY_U8: [u8; 0] = [];
 
-impl Language {
-    pub async fn eval<T, U>(
+impl Backend {
+    async fn eval(
         &self,
-        code: T,
         timeout: Option<usize>,
-        context: Option<U>,
-    ) -> Result<String, String>
-    where
-        T: AsRef<str>,
-        U: AsRef<str>,
-    {
-        debug!("evaluating {}: \"{}\"", self.name, code.as_ref());
-        let timeout = match timeout {
-            Some(0) => None,
-            Some(n) => Some(n),
-            None => self.timeout,
-        };
-        match self.backend {
-            Backend::Exec(ref lang) => {
-                eval::exec(lang.clone(), timeout, self.wrap_code(code.as_ref())).await
+        context: Option<&str>,
+        program: &Program<'_>,
+        journal: Option<Arc<Journal>>,
+        out: &mut Output,
+    ) -> Result<(), EvalError> {
+        match self {
+            Backend::Exec(ref cmdline) => {
+                eval::exec(cmdline.clone(), None, timeout, context, program, out).await
+            }
+            Backend::Sandbox(ref cmdline, ref sandbox) => {
+                eval::exec(
+                    cmdline.clone(),
+                    Some(sandbox.clone()),
+                    timeout,
+                    context,
+                    program,
+                    out,
+                )
+                .await
             }
-            Backend::UnixSocket(ref lang) => {
+            Backend::UnixSocket(ref lang, ref daemon) => {
                 eval::unix(
                     lang.clone(),
+                    daemon.clone(),
+                    journal,
                     timeout,
-                    context.map(|x| x.as_ref().to_owned()), // FIXME copy :(
-                    self.wrap_code(code.as_ref()),
+                    context,
+                    program,
+                    out,
                 )
                 .await
             }
-            _ => Ok("Unimplemented".to_owned()),
+            Backend::Network(ref lang) => {
+                eval::network(lang.clone(), journal, timeout, context, program, out).await
+            }
+        }
+    }
+
+    async fn context_op(
+        &self,
+        timeout: Option<usize>,
+        op: Op,
+        context: Option<&str>,
+    ) -> Result<Vec<ContextInfo>, EvalError> {
+        match self {
+            Backend::Exec(_) | Backend::Sandbox(..) => Err(EvalError::Unsupported(
+                "programs run by cmdline have no contexts".to_owned(),
+            )),
+            Backend::UnixSocket(ref lang, ref daemon) => {
+                eval::unix_op(lang.clone(), daemon.clone(), timeout, op, context).await
+            }
+            Backend::Network(ref lang) => {
+                eval::network_op(lang.clone(), timeout, op, context).await
+            }
         }
     }
+}
+
+impl Language {
+    /// The name the language is configured under.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
 
-    fn wrap_code(&self, raw: &str) -> String {
-        let mut code = String::with_capacity(raw.len());
+    /// The name to show to users, which defaults to the configured name.
+    pub fn display_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
 
-        if let Some(ref prefix) = self.code_before {
-            code.push_str(prefix);
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// The extension of source files in the language, without the dot.
+    pub fn extension(&self) -> Option<&str> {
+        self.extension.as_deref()
+    }
+
+    /// The name of the language for syntax highlighters.
+    pub fn highlight(&self) -> Option<&str> {
+        self.highlight.as_deref()
+    }
+
+    /// The timeout in seconds for requests that do not set one, or `None` if there is none.
+    pub fn timeout(&self) -> Option<usize> {
+        self.timeout.filter(|&n| n != 0)
+    }
+
+    pub fn backend_kind(&self) -> BackendKind {
+        match self.backend {
+            Backend::Exec(_) => BackendKind::Exec,
+            Backend::Sandbox(..) => BackendKind::Sandbox,
+            Backend::Network(_) => BackendKind::Network,
+            Backend::UnixSocket(..) => BackendKind::UnixSocket,
+        }
+    }
+
+    pub async fn eval(&self, request: EvalRequest) -> Result<EvalResult, EvalError> {
+        let mut out = Output::new(None, self.output_limits(&request));
+        self.run(&request, &mut out).await?;
+        Ok(out.finish())
+    }
+
+    /// Like `eval`, but yields output as the program produces it. The stream ends with either
+    /// `EvalEvent::Finished` or an error.
+    pub fn eval_stream(
+        &self,
+        request: EvalRequest,
+    ) -> impl Stream<Item = Result<EvalEvent, EvalError>> + Send + 'static {
+        let (tx, rx) = mpsc::unbounded();
+        let lang = self.clone();
+        let run = async move {
+            let mut out = Output::new(Some(tx.clone()), lang.output_limits(&request));
+            let res = lang
+                .run(&request, &mut out)
+                .await
+                .map(|()| EvalEvent::Finished(out.finish()));
+            let _ = tx.unbounded_send(res);
+        };
+        // the evaluation itself sends everything through the channel, so it only has to be
+        // polled alongside it
+        stream::select(rx, stream::once(run).filter_map(|()| future::ready(None)))
+    }
+
+    /// Clears the state of `context`, including any incomplete input, in a persistent
+    /// evaluator.
+    pub async fn reset_context(&self, context: &str) -> Result<(), EvalError> {
+        if let Some(ref journal) = self.journal {
+            journal.clear(context);
         }
+        self.backend
+            .context_op(self.timeout(), Op::Reset, Some(context))
+            .await
+            .map(|_| ())
+    }
+
+    /// Makes a persistent evaluator forget `context` entirely.
+    pub async fn drop_context(&self, context: &str) -> Result<(), EvalError> {
+        if let Some(ref contexts) = self.contexts {
+            contexts.forget(context);
+        }
+        if let Some(ref journal) = self.journal {
+            journal.clear(context);
+        }
+        self.backend
+            .context_op(self.timeout(), Op::Drop, Some(context))
+            .await
+            .map(|_| ())
+    }
+
+    /// The contexts a persistent evaluator is keeping alive.
+    pub async fn list_contexts(&self) -> Result<Vec<ContextInfo>, EvalError> {
+        self.backend
+            .context_op(self.timeout(), Op::List, None)
+            .await
+    }
+
+    /// Runs the request's program under ptrace instead of evaluating it, and returns the syscalls
+    /// it made and how it exited, to help write the language's syscall list. Only languages run
+    /// by `cmdline` can be traced.
+    ///
+    /// This blocks until the program has exited, and waits for any child process of the caller,
+    /// so it is meant for tools rather than for use alongside evaluations.
+    pub fn trace_syscalls(
+        &self,
+        request: &EvalRequest,
+    ) -> Result<(SyscallList, ExitStatus), EvalError> {
+        let (cmdline, sandbox) = match self.backend {
+            Backend::Exec(ref cmdline) => (cmdline, None),
+            Backend::Sandbox(ref cmdline, ref sandbox) => (cmdline, Some(&**sandbox)),
+            _ => {
+                return Err(EvalError::Unsupported(
+                    "only programs run by cmdline can be traced".to_owned(),
+                ))
+            }
+        };
+        let (code, variant) = self.wrap_code(&request.code, request.template.as_deref())?;
+        let program = Program {
+            code: &code,
+            variant,
+            stdin: request.stdin.as_deref(),
+            args: &request.args,
+            env: &request.env,
+        };
+        let (syscalls, status) = eval::trace(
+            cmdline,
+            sandbox,
+            self.time_limit(request.time_limit),
+            request.context.as_deref(),
+            &program,
+        )?;
+        Ok((SyscallList::from_numbers(syscalls), status))
+    }
 
-        code.push_str(raw);
+    async fn run(&self, request: &EvalRequest, out: &mut Output) -> Result<(), EvalError> {
+        match request.requester {
+            Some(ref requester) => debug!(
+                "evaluating {} for {}: \"{}\"",
+                self.name, requester, request.code
+            ),
+            None => debug!("evaluating {}: \"{}\"", self.name, request.code),
+        }
+        let _permits = self.acquire(request.priority).await?;
+        let context = request.context.as_deref();
+        if let Some(context) = context {
+            self.track_context(context);
+        }
+        let (code, variant) = self.wrap_code(&request.code, request.template.as_deref())?;
+        let program = Program {
+            code: &code,
+            variant,
+            stdin: request.stdin.as_deref(),
+            args: &request.args,
+            env: &request.env,
+        };
+        self.backend
+            .eval(
+                self.time_limit(request.time_limit),
+                context,
+                &program,
+                self.journal.clone(),
+                out,
+            )
+            .await
+    }
 
-        if let Some(ref postfix) = self.code_after {
-            code.push_str(postfix);
+    /// Notes that `context` is being used, and drops contexts that have been idle for longer
+    /// than the language's `context_ttl` in the background.
+    fn track_context(&self, context: &str) {
+        let contexts = match self.contexts {
+            Some(ref contexts) => contexts,
+            None => return,
+        };
+        contexts.touch(context);
+        let expired = contexts.take_expired();
+        if expired.is_empty() {
+            return;
         }
+        let (backend, journal, timeout) =
+            (self.backend.clone(), self.journal.clone(), self.timeout());
+        tokio::spawn(async move {
+            for key in expired {
+                debug!("dropping idle context {}", key);
+                if let Some(ref journal) = journal {
+                    journal.clear(&key);
+                }
+                if let Err(e) = backend.context_op(timeout, Op::Drop, Some(&key)).await {
+                    warn!("failed to drop idle context {}: {}", key, e);
+                }
+            }
+        });
+    }
+
+    /// Waits for a slot in the language's queue, then in the global queue, so that a language
+    /// that is at its limit does not hold up others.
+    async fn acquire(&self, priority: i32) -> Result<(Option<Permit>, Option<Permit>), EvalError> {
+        let permit = match self.limiter {
+            Some(ref l) => Some(l.acquire(priority).await?),
+            None => None,
+        };
+        let global_permit = match self.global_limiter {
+            Some(ref l) => Some(l.acquire(priority).await?),
+            None => None,
+        };
+        Ok((permit, global_permit))
+    }
 
-        code
+    fn time_limit(&self, time_limit: TimeLimit) -> Option<usize> {
+        match time_limit {
+            TimeLimit::Default => self.timeout(),
+            TimeLimit::Unlimited => None,
+            TimeLimit::Seconds(n) => Some(n.get()),
+        }
+    }
+
+    /// The language's output limits, with the request's overrides applied.
+    fn output_limits(&self, request: &EvalRequest) -> OutputLimits {
+        let limit = |requested: Option<usize>, configured| match requested {
+            Some(0) => None,
+            Some(n) => Some(n),
+            None => configured,
+        };
+        OutputLimits {
+            bytes: limit(request.max_output_bytes, self.output_limits.bytes),
+            lines: limit(request.max_output_lines, self.output_limits.lines),
+        }
+    }
+
+    /// Returns the code wrapped in its template, and the template's name.
+    fn wrap_code<'a>(
+        &'a self,
+        raw: &str,
+        template: Option<&'a str>,
+    ) -> Result<(String, Option<&'a str>), EvalError> {
+        let (template, variant) = match template {
+            Some(name) => (
+                self.templates
+                    .get(name)
+                    .ok_or_else(|| EvalError::UnknownTemplate(name.to_owned()))?,
+                Some(name),
+            ),
+            None => match self.wrap_rules.iter().find(|r| r.matches(raw)) {
+                Some(rule) => match rule.template() {
+                    Some(name) => (&self.templates[name], Some(name)),
+                    None => return Ok((raw.to_owned(), None)),
+                },
+                None => (&self.default_template, self.default_variant.as_deref()),
+            },
+        };
+        Ok((template.render(raw, &self.name, &self.vars), variant))
     }
 }
 
@@ -194,4 +909,176 @@ cmdline = ["rustc", "-O"]
 "#;
         println!("{:#?}", super::EvalService::from_toml(toml).unwrap());
     }
+
+    #[test]
+    fn test_reload() {
+        let service = super::EvalService::from_toml(
+            r#"
+timeout = 20
+
+[languages.rs]
+cmdline = ["rustc", "-O"]
+"#,
+        )
+        .unwrap();
+        let rs = service.get("rs").unwrap();
+
+        assert!(service.reload_from_toml("timeout = ").is_err());
+        assert_eq!(service.timeout(), 20);
+
+        service
+            .reload_from_toml(
+                r#"
+timeout = 10
+
+[languages.c]
+cmdline = ["cc"]
+"#,
+            )
+            .unwrap();
+        assert_eq!(service.timeout(), 10);
+        assert!(service.get("rs").is_none());
+        assert!(service.get("c").is_some());
+        assert_eq!(rs.timeout, Some(20));
+    }
+
+    #[test]
+    fn test_aliases() {
+        let service = super::EvalService::from_toml(
+            r#"
+timeout = 20
+
+[languages.rs]
+aliases = ["rust"]
+display_name = "Rust"
+cmdline = ["rustc", "-O"]
+"#,
+        )
+        .unwrap();
+        let rs = service.get("rust").unwrap();
+        assert_eq!(rs.name(), "rs");
+        assert_eq!(rs.display_name(), "Rust");
+        assert_eq!(rs.backend_kind(), super::BackendKind::Exec);
+        assert_eq!(service.langs().len(), 1);
+
+        assert!(service
+            .reload_from_toml(
+                r#"
+timeout = 20
+
+[languages.rs]
+aliases = ["c"]
+cmdline = ["rustc", "-O"]
+
+[languages.c]
+cmdline = ["cc"]
+"#,
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_sandbox_workdir() {
+        let err = super::EvalService::from_toml(
+            r#"
+timeout = 20
+
+[languages.sh]
+cmdline = ["/bin/sh", "-c", "cd {WORKDIR}"]
+sandbox = { root = "/" }
+"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("{WORKDIR} is not mounted"));
+    }
+
+    #[tokio::test]
+    async fn test_exec_exit() {
+        let service = super::EvalService::from_toml(
+            r#"
+timeout = 20
+
+[languages.sh]
+cmdline = ["/bin/sh", "-c", "exit 3"]
+"#,
+        )
+        .unwrap();
+        let result = service
+            .get("sh")
+            .unwrap()
+            .eval(super::EvalRequest::new(""))
+            .await
+            .unwrap();
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(result.signal, None);
+        assert!(result.usage.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_exec_no_timeout() {
+        // a timeout of 0 means no limit, not an immediate timeout
+        let service = super::EvalService::from_toml(
+            r#"
+timeout = 0
+
+[languages.sh]
+cmdline = ["/bin/sh", "-c", "sleep 0.1; echo done"]
+"#,
+        )
+        .unwrap();
+        let result = service
+            .get("sh")
+            .unwrap()
+            .eval(super::EvalRequest::new(""))
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "done\n");
+        assert_eq!(result.exit_code, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_exec_timeout() {
+        let pids = std::env::temp_dir().join(format!("evalbot-test-{}", std::process::id()));
+        let service = super::EvalService::from_toml(&format!(
+            r#"
+timeout = 1
+
+[languages.sh]
+cmdline = ["/bin/sh", "-c", "sleep 100 & echo $$ $! > {}; sleep 100"]
+"#,
+            pids.display()
+        ))
+        .unwrap();
+        let start = std::time::Instant::now();
+        match service
+            .get("sh")
+            .unwrap()
+            .eval(super::EvalRequest::new(""))
+            .await
+        {
+            Err(super::EvalError::Timeout) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+        // the shell and the sleep it left in the background are gone, or at least dead
+        let pids = std::fs::read_to_string(&pids).and_then(|p| {
+            std::fs::remove_file(&pids)?;
+            Ok(p)
+        });
+        for pid in pids.unwrap().split_whitespace() {
+            let gone = (0..100).any(|_| {
+                let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid));
+                let dead = match stat {
+                    Ok(stat) => stat.rsplit(") ").next().unwrap().starts_with('Z'),
+                    Err(_) => true,
+                };
+                if !dead {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                dead
+            });
+            assert!(gone, "process {} outlived the timeout", pid);
+        }
+    }
 }
@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use futures::channel::oneshot;
+
+use crate::EvalError;
+
+/// Limits how many evaluations run at once. Evaluations over the limit wait in a queue ordered by
+/// priority, and first-come, first-served within the same priority.
+#[derive(Debug)]
+pub struct Limiter {
+    max_concurrent: usize,
+    max_queue: Option<usize>,
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    running: usize,
+    queue: VecDeque<(i32, oneshot::Sender<()>)>,
+}
+
+/// A slot in a `Limiter`, released on drop.
+#[derive(Debug)]
+pub struct Permit(Arc<Limiter>);
+
+/// Gives back the slot if the wait is abandoned after it was handed over.
+struct Waiter {
+    rx: oneshot::Receiver<()>,
+    limiter: Arc<Limiter>,
+}
+
+impl Limiter {
+    pub fn new(max_concurrent: usize, max_queue: Option<usize>) -> Arc<Self> {
+        Arc::new(Limiter {
+            max_concurrent,
+            max_queue,
+            state: Mutex::new(LimiterState::default()),
+        })
+    }
+
+    /// Returns `prev` if it has the same limits, so that running evaluations still count
+    /// after a reload.
+    pub fn reuse(
+        prev: Option<&Arc<Limiter>>,
+        max_concurrent: Option<usize>,
+        max_queue: Option<usize>,
+    ) -> Option<Arc<Self>> {
+        let max_concurrent = max_concurrent.filter(|&n| n != 0)?;
+        match prev {
+            Some(prev) if prev.max_concurrent == max_concurrent && prev.max_queue == max_queue => {
+                Some(prev.clone())
+            }
+            _ => Some(Limiter::new(max_concurrent, max_queue)),
+        }
+    }
+
+    pub async fn acquire(self: &Arc<Self>, priority: i32) -> Result<Permit, EvalError> {
+        let rx = {
+            let mut state = self.lock();
+            if state.running < self.max_concurrent && state.queue.is_empty() {
+                state.running += 1;
+                return Ok(Permit(self.clone()));
+            }
+            if self.max_queue.is_some_and(|max| state.queue.len() >= max) {
+                return Err(EvalError::Busy);
+            }
+            let (tx, rx) = oneshot::channel();
+            let pos = state
+                .queue
+                .iter()
+                .position(|&(p, _)| p < priority)
+                .unwrap_or(state.queue.len());
+            state.queue.insert(pos, (priority, tx));
+            rx
+        };
+
+        let mut waiter = Waiter {
+            rx,
+            limiter: self.clone(),
+        };
+        match (&mut waiter.rx).await {
+            Ok(()) => Ok(Permit(self.clone())),
+            Err(oneshot::Canceled) => Err(EvalError::Busy),
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.lock();
+        while let Some((_, next)) = state.queue.pop_front() {
+            if next.send(()).is_ok() {
+                // the slot passes straight to the next in line
+                return;
+            }
+        }
+        state.running -= 1;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LimiterState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl PartialEq for Limiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_concurrent == other.max_concurrent && self.max_queue == other.max_queue
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.rx.close();
+        if let Ok(Some(())) = self.rx.try_recv() {
+            self.limiter.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Limiter;
+    use crate::EvalError;
+
+    #[tokio::test]
+    async fn test_limiter() {
+        let limiter = Limiter::new(1, Some(1));
+        let first = limiter.acquire(0).await.unwrap();
+
+        let l = limiter.clone();
+        let second = tokio::spawn(async move { l.acquire(0).await.map(drop) });
+        tokio::task::yield_now().await;
+        assert!(matches!(limiter.acquire(0).await, Err(EvalError::Busy)));
+
+        drop(first);
+        second.await.unwrap().unwrap();
+        drop(limiter.acquire(0).await.unwrap());
+
+        // a higher priority goes ahead of those already waiting
+        let limiter = Limiter::new(1, None);
+        let first = limiter.acquire(0).await.unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for priority in &[0, 1, 0] {
+            let (l, tx, priority) = (limiter.clone(), tx.clone(), *priority);
+            tokio::spawn(async move {
+                let _permit = l.acquire(priority).await.unwrap();
+                tx.send(priority).unwrap();
+            });
+            tokio::task::yield_now().await;
+        }
+        drop(first);
+        for expected in &[1, 0, 0] {
+            assert_eq!(rx.recv().await, Some(*expected));
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! A process between the bot and each program it runs, which waits for the program, exits the
+//! same way, and reports how it went.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::time::Duration;
+
+use tokio::process::Command;
+
+use crate::result::ResourceUsage;
+use crate::sandbox::{check, ptrace, syscall_number, Cgroup, TRACE_OPTIONS};
+
+/// The report is made of native-endian `u64`s: the number of the syscall the program was killed
+/// for making, or `NO_SYSCALL`, its user and system time in microseconds, and its peak resident
+/// set size in KiB.
+const REPORT_LEN: usize = 4 * 8;
+const NO_SYSCALL: u64 = u64::MAX;
+
+/// The bot's end of a monitor, to keep until the program has exited.
+#[derive(Debug)]
+pub struct Monitor {
+    report: File,
+    cgroup: Option<Cgroup>,
+}
+
+/// How a monitored program went.
+#[derive(Default, PartialEq, Debug)]
+pub struct Report {
+    /// The syscall the program was killed for making, if it was.
+    pub bad_syscall: Option<u32>,
+    pub usage: Option<ResourceUsage>,
+    /// Whether anything in the program's cgroup was killed for running out of memory.
+    pub oom_killed: bool,
+}
+
+impl Monitor {
+    /// Returns a monitor and the end of its report pipe to pass to `wait_and_exit`.
+    pub fn new() -> io::Result<(Self, File)> {
+        let mut pipe = [0; 2];
+        check(unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) })?;
+        let (report, writer) = unsafe { (File::from_raw_fd(pipe[0]), File::from_raw_fd(pipe[1])) };
+        Ok((
+            Monitor {
+                report,
+                cgroup: None,
+            },
+            writer,
+        ))
+    }
+
+    /// Makes `cmd` run under a monitor. Sandboxes start their own, in `Setup::enter`.
+    pub fn apply(cmd: &mut Command) -> io::Result<Self> {
+        let (monitor, writer) = Monitor::new()?;
+        unsafe {
+            cmd.pre_exec(move || match check(libc::fork())? {
+                // if the bot kills the process it started, this one goes with it
+                0 => check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0)).map(|_| ()),
+                pid => wait_and_exit(pid, Some(writer.as_raw_fd()), false),
+            });
+        }
+        Ok(monitor)
+    }
+
+    /// Keeps the program's cgroup until the program has exited, and checks it for OOM kills.
+    pub fn set_cgroup(&mut self, cgroup: Option<Cgroup>) {
+        self.cgroup = cgroup;
+    }
+
+    /// Reads the monitor's report. Only valid once the program has exited.
+    pub fn report(&self) -> Report {
+        let mut report = Report::default();
+        let mut buf = [0; REPORT_LEN];
+        if (&self.report).read_exact(&mut buf).is_ok() {
+            let field = |i: usize| u64::from_ne_bytes(buf[i * 8..][..8].try_into().unwrap());
+            if field(0) != NO_SYSCALL {
+                report.bad_syscall = Some(field(0) as u32);
+            }
+            report.usage = Some(ResourceUsage {
+                user_time: Duration::from_micros(field(1)),
+                system_time: Duration::from_micros(field(2)),
+                peak_memory: field(3) * 1024,
+            });
+        }
+        if let Some(ref cgroup) = self.cgroup {
+            report.oom_killed = cgroup.stat("memory.events", "oom_kill").unwrap_or(0) > 0;
+        }
+        report
+    }
+}
+
+/// Waits for the program and exits the same way, so that the bot sees how the program ended, and
+/// writes the report to `report` if there is one.
+///
+/// If `traced`, the program stops itself before its seccomp filter is installed, and the filter
+/// stops it for syscalls it may not make instead of killing it. The first such syscall is
+/// reported, and the program is killed and reported as if by `SIGSYS`.
+pub unsafe fn wait_and_exit(pid: libc::pid_t, report: Option<libc::c_int>, traced: bool) -> ! {
+    libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0);
+    // when its process group is terminated, the program gets a chance to exit on its own
+    libc::signal(libc::SIGTERM, libc::SIG_IGN);
+    // the program's pipes must close when it exits, not when this process does
+    match report {
+        Some(fd) => {
+            if fd > 0 {
+                libc::syscall(libc::SYS_close_range, 0, fd - 1, 0);
+            }
+            libc::syscall(libc::SYS_close_range, fd + 1, libc::c_uint::MAX, 0);
+        }
+        None => {
+            libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0);
+        }
+    }
+    let mut status = 0;
+    let mut usage = mem::zeroed::<libc::rusage>();
+    if wait(pid, &mut status, 0, &mut usage).is_err() {
+        libc::_exit(1);
+    }
+    let mut bad_syscall = None;
+    if traced && libc::WIFSTOPPED(status) {
+        if ptrace(libc::PTRACE_SETOPTIONS, pid, TRACE_OPTIONS as usize).is_err()
+            || ptrace(libc::PTRACE_CONT, pid, 0).is_err()
+        {
+            libc::kill(pid, libc::SIGKILL);
+        }
+        loop {
+            let mut event = 0;
+            let mut event_usage = mem::zeroed::<libc::rusage>();
+            let tracee = match wait(-1, &mut event, libc::__WALL, &mut event_usage) {
+                Ok(tracee) => tracee,
+                // nothing left to trace
+                Err(_) => break,
+            };
+            if !libc::WIFSTOPPED(event) {
+                if tracee == pid {
+                    status = event;
+                    usage = event_usage;
+                }
+                continue;
+            }
+            let signal = match event >> 16 {
+                0 => match libc::WSTOPSIG(event) {
+                    // children start out stopped
+                    libc::SIGSTOP => 0,
+                    signal => signal,
+                },
+                libc::PTRACE_EVENT_SECCOMP => {
+                    if bad_syscall.is_none() {
+                        bad_syscall = syscall_number(tracee).ok();
+                    }
+                    // the syscall is skipped only if the tracee has a fatal signal pending when it
+                    // resumes, which killing the program does not guarantee for its children
+                    libc::kill(tracee, libc::SIGKILL);
+                    // the program is the init of its PID namespace, so this ends everything in it
+                    libc::kill(pid, libc::SIGKILL);
+                    continue;
+                }
+                _ => 0,
+            };
+            let _ = ptrace(libc::PTRACE_CONT, tracee, signal as usize);
+        }
+    }
+    if let Some(fd) = report {
+        let micros = |t: libc::timeval| t.tv_sec as u64 * 1_000_000 + t.tv_usec as u64;
+        let fields = [
+            bad_syscall.map_or(NO_SYSCALL, u64::from),
+            micros(usage.ru_utime),
+            micros(usage.ru_stime),
+            usage.ru_maxrss as u64,
+        ];
+        let mut buf = [0u8; REPORT_LEN];
+        for (chunk, field) in buf.chunks_mut(8).zip(&fields) {
+            chunk.copy_from_slice(&field.to_ne_bytes());
+        }
+        libc::write(fd, buf.as_ptr().cast(), REPORT_LEN);
+    }
+    if bad_syscall.is_some() {
+        // the status of a process killed by SIGSYS
+        exit_like(libc::SIGSYS);
+    }
+    exit_like(status)
+}
+
+/// Sends `signal` to the process group led by the process with PID `pgid`.
+pub fn signal_group(pgid: libc::pid_t, signal: libc::c_int) {
+    unsafe {
+        libc::killpg(pgid, signal);
+    }
+}
+
+/// Whether the child with PID `pid` has exited. It is not reaped, so neither its PID nor its
+/// process group can be reused until it is waited for.
+pub fn has_exited(pid: libc::pid_t) -> bool {
+    let mut info = unsafe { mem::zeroed::<libc::siginfo_t>() };
+    let res = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+    // with WNOHANG, the PID is left 0 while the child is running
+    res == -1 || unsafe { info.si_pid() } != 0
+}
+
+/// Exits with `status` as returned by `waitpid`.
+unsafe fn exit_like(status: libc::c_int) -> ! {
+    if libc::WIFSIGNALED(status) {
+        let signal = libc::WTERMSIG(status);
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+    }
+    libc::_exit(libc::WEXITSTATUS(status))
+}
+
+/// `wait4`, retried when interrupted. `usage` covers the process and the children it waited for.
+unsafe fn wait(
+    pid: libc::pid_t,
+    status: &mut libc::c_int,
+    options: libc::c_int,
+    usage: &mut libc::rusage,
+) -> io::Result<libc::pid_t> {
+    loop {
+        match check(libc::wait4(pid, status, options, usage)) {
+            Err(e) if e.raw_os_error() == Some(libc::EINTR) => continue,
+            res => return res,
+        }
+    }
+}
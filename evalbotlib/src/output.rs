@@ -0,0 +1,223 @@
+use std::time::Instant;
+
+use futures::channel::mpsc::UnboundedSender;
+
+use crate::{EvalError, EvalEvent, EvalResult};
+
+pub type EventSender = UnboundedSender<Result<EvalEvent, EvalError>>;
+
+/// Limits on the combined size of stdout and stderr.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct OutputLimits {
+    pub bytes: Option<usize>,
+    pub lines: Option<usize>,
+}
+
+/// Collects the output of an evaluation into an `EvalResult`, forwarding it to a stream as it
+/// arrives if there is one.
+///
+/// Output beyond the limits is discarded, and the result is marked as truncated.
+pub struct Output {
+    result: EvalResult,
+    start: Instant,
+    stdout: Utf8Decoder,
+    stderr: Utf8Decoder,
+    events: Option<EventSender>,
+    limits: OutputLimits,
+    bytes: usize,
+    lines: usize,
+}
+
+impl Output {
+    pub fn new(events: Option<EventSender>, limits: OutputLimits) -> Self {
+        Output {
+            result: EvalResult::default(),
+            start: Instant::now(),
+            stdout: Utf8Decoder::default(),
+            stderr: Utf8Decoder::default(),
+            events,
+            limits,
+            bytes: 0,
+            lines: 0,
+        }
+    }
+
+    /// Whether the output limits have been reached. Anything further will be discarded.
+    pub fn is_full(&self) -> bool {
+        self.result.truncated
+    }
+
+    /// How many more bytes of output will be kept.
+    pub fn remaining_bytes(&self) -> usize {
+        if self.is_full() {
+            0
+        } else {
+            self.limits
+                .bytes
+                .map_or(usize::MAX, |max| max.saturating_sub(self.bytes))
+        }
+    }
+
+    pub fn stdout(&mut self, data: &[u8]) {
+        let s = self.stdout.decode(data);
+        self.push_stdout(s);
+    }
+
+    pub fn stderr(&mut self, data: &[u8]) {
+        let s = self.stderr.decode(data);
+        self.push_stderr(s);
+    }
+
+    pub fn result_mut(&mut self) -> &mut EvalResult {
+        &mut self.result
+    }
+
+    pub fn finish(mut self) -> EvalResult {
+        let s = self.stdout.flush();
+        self.push_stdout(s);
+        let s = self.stderr.flush();
+        self.push_stderr(s);
+        self.result.wall_time = self.start.elapsed();
+        self.result
+    }
+
+    fn push_stdout(&mut self, s: String) {
+        let s = self.limit(s);
+        if !s.is_empty() {
+            self.result.stdout.push_str(&s);
+            self.send(EvalEvent::Stdout(s));
+        }
+    }
+
+    fn push_stderr(&mut self, s: String) {
+        let s = self.limit(s);
+        if !s.is_empty() {
+            self.result.stderr.push_str(&s);
+            self.send(EvalEvent::Stderr(s));
+        }
+    }
+
+    /// Cuts `s` down to what still fits within the limits.
+    fn limit(&mut self, mut s: String) -> String {
+        if s.is_empty() {
+            return s;
+        }
+        let mut end = s.len();
+        if self.remaining_bytes() < end {
+            end = floor_char_boundary(&s, self.remaining_bytes());
+        }
+        if let Some(max) = self.limits.lines {
+            let left = max.saturating_sub(self.lines);
+            let cut = if left == 0 {
+                Some(0)
+            } else {
+                s[..end]
+                    .match_indices('\n')
+                    .nth(left - 1)
+                    .map(|(i, _)| i + 1)
+            };
+            if let Some(cut) = cut {
+                end = end.min(cut);
+            }
+        }
+        if end < s.len() {
+            s.truncate(end);
+            self.result.truncated = true;
+        }
+        self.bytes += s.len();
+        self.lines += s.matches('\n').count();
+        s
+    }
+
+    fn send(&mut self, event: EvalEvent) {
+        if let Some(ref events) = self.events {
+            // the receiver going away just means nobody is listening any more
+            let _ = events.unbounded_send(Ok(event));
+        }
+    }
+}
+
+/// Decodes UTF-8 that may be split at arbitrary points, holding back incomplete characters
+/// until the rest arrives.
+#[derive(Default)]
+struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    fn decode(&mut self, data: &[u8]) -> String {
+        self.pending.extend_from_slice(data);
+        let keep = incomplete_tail(&self.pending);
+        let rest = self.pending.split_off(self.pending.len() - keep);
+        let s = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending = rest;
+        s
+    }
+
+    fn flush(&mut self) -> String {
+        let s = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        s
+    }
+}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+/// Returns the length of an incomplete UTF-8 sequence at the end of `b`.
+fn incomplete_tail(b: &[u8]) -> usize {
+    for i in 1..=b.len().min(3) {
+        let c = b[b.len() - i];
+        if c & 0xC0 != 0x80 {
+            let need = match c {
+                0xF0..=0xFF => 4,
+                0xE0..=0xEF => 3,
+                0xC0..=0xDF => 2,
+                _ => 1,
+            };
+            return if need > i { i } else { 0 };
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Output, OutputLimits};
+
+    #[test]
+    fn test_output_limits() {
+        let limits = OutputLimits {
+            bytes: Some(12),
+            lines: Some(2),
+        };
+        let mut out = Output::new(None, limits);
+        out.stdout(b"a\nb");
+        out.stderr(b"\nc\n");
+        assert!(out.is_full());
+        out.stdout(b"d\n");
+        let result = out.finish();
+        assert_eq!((&*result.stdout, &*result.stderr), ("a\nb", "\n"));
+        assert!(result.truncated);
+
+        let mut out = Output::new(None, limits);
+        out.stdout("ééééééé".as_bytes());
+        assert_eq!(out.finish().stdout, "éééééé");
+    }
+
+    #[test]
+    fn test_utf8_decoder() {
+        let mut d = super::Utf8Decoder::default();
+        let s = "héllo → wörld".as_bytes();
+        let mut r = String::new();
+        for chunk in s.chunks(3) {
+            r.push_str(&d.decode(chunk));
+        }
+        r.push_str(&d.flush());
+        assert_eq!(r, "héllo → wörld");
+    }
+}
@@ -0,0 +1,134 @@
+//! Presets that languages can extend, so that settings shared by several languages are only
+//! written once.
+
+use toml::value::{Table, Value};
+
+/// Returns the configuration of a language with the presets it `extends` merged in.
+///
+/// Keys set by the language override those of its preset, which override those of the preset it
+/// extends in turn, and so on. Tables like `templates` are merged key by key. `cmdline_prefix` is
+/// prepended to `cmdline`.
+pub fn resolve(lang: &Table, presets: &Table) -> Result<Table, String> {
+    let mut layers = vec![lang];
+    let mut chain = Vec::new();
+    let mut next = extends(lang)?;
+    while let Some(name) = next {
+        let cycle = chain.contains(&name);
+        chain.push(name);
+        if cycle {
+            return Err(format!(
+                "presets extend each other in a cycle: {}",
+                chain.join(" -> ")
+            ));
+        }
+        let preset = match presets.get(name) {
+            Some(Value::Table(preset)) => preset,
+            Some(_) => return Err(format!("preset {} is not a table", name)),
+            None => return Err(format!("unknown preset {}", name)),
+        };
+        next = extends(preset)?;
+        layers.push(preset);
+    }
+
+    let mut merged = Table::new();
+    for layer in layers.into_iter().rev() {
+        merge(&mut merged, layer);
+    }
+    merged.remove("extends");
+    if let Some(prefix) = merged.remove("cmdline_prefix") {
+        let mut prefix = match prefix {
+            Value::Array(prefix) => prefix,
+            _ => return Err("cmdline_prefix is not an array".to_owned()),
+        };
+        match merged.remove("cmdline") {
+            Some(Value::Array(cmdline)) => {
+                prefix.extend(cmdline);
+                merged.insert("cmdline".to_owned(), Value::Array(prefix));
+            }
+            Some(_) => return Err("cmdline is not an array".to_owned()),
+            // not run by cmdline, so the prefix does not apply
+            None => {}
+        }
+    }
+    Ok(merged)
+}
+
+fn extends(table: &Table) -> Result<Option<&str>, String> {
+    match table.get("extends") {
+        Some(Value::String(name)) => Ok(Some(name)),
+        Some(_) => Err("extends is not a string".to_owned()),
+        None => Ok(None),
+    }
+}
+
+fn merge(into: &mut Table, from: &Table) {
+    for (key, value) in from {
+        match (into.get_mut(key), value) {
+            (Some(Value::Table(into)), Value::Table(from)) => merge(into, from),
+            _ => {
+                into.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use toml::value::{Table, Value};
+
+    fn table(toml: &str) -> Table {
+        match toml.parse::<Value>().unwrap() {
+            Value::Table(table) => table,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let presets = table(
+            r#"
+[playpen]
+cmdline_prefix = ["playpen", "{TIMEOUT}"]
+timeout = 10
+templates = { a = "A{CODE}" }
+
+[compiled]
+extends = "playpen"
+max_concurrent = 2
+templates = { b = "B{CODE}" }
+
+[loop]
+extends = "cycle"
+
+[cycle]
+extends = "loop"
+"#,
+        );
+        let lang = table(
+            r#"
+extends = "compiled"
+cmdline = ["rustc"]
+timeout = 20
+"#,
+        );
+        assert_eq!(
+            super::resolve(&lang, &presets).unwrap(),
+            table(
+                r#"
+cmdline = ["playpen", "{TIMEOUT}", "rustc"]
+timeout = 20
+max_concurrent = 2
+templates = { a = "A{CODE}", b = "B{CODE}" }
+"#
+            )
+        );
+
+        let lang = table(r#"extends = "loop""#);
+        assert_eq!(
+            super::resolve(&lang, &presets).unwrap_err(),
+            "presets extend each other in a cycle: loop -> cycle -> loop"
+        );
+        let lang = table(r#"extends = "missing""#);
+        assert!(super::resolve(&lang, &presets).is_err());
+    }
+}
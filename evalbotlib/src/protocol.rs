@@ -0,0 +1,455 @@
+//! Wire formats for persistent evaluators. See README.md for the protocol description.
+
+use std::convert::TryFrom;
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use log::trace;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::context::ContextInfo;
+use crate::output::Output;
+use crate::EvalError;
+
+pub const MAGIC: &[u8; 4] = b"EVAL";
+pub const LATEST_VERSION: u32 = 2;
+
+// request frame types
+const REQ_END: u32 = 0;
+const REQ_TIMEOUT: u32 = 1;
+const REQ_CONTEXT: u32 = 2;
+const REQ_CODE: u32 = 3;
+const REQ_OP: u32 = 4;
+const REQ_STDIN: u32 = 5;
+
+// response frame types
+const RESP_END: u32 = 0;
+const RESP_STDOUT: u32 = 1;
+const RESP_STDERR: u32 = 2;
+const RESP_VALUE: u32 = 3;
+const RESP_STATUS: u32 = 4;
+const RESP_ERROR: u32 = 5;
+const RESP_CONTEXT: u32 = 6;
+
+/// The longest response frame accepted, other than output, which is cut to the output limits.
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+/// Operations other than evaluation, sent in an op frame.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Op {
+    Reset = 1,
+    Drop = 2,
+    List = 3,
+}
+
+pub fn check_version(version: u32) -> Result<(), EvalError> {
+    if version == 0 || version > LATEST_VERSION {
+        Err(EvalError::Config(format!(
+            "unsupported protocol version {}",
+            version
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn make_v1_request<T, U>(timeout: Option<usize>, context: Option<T>, code: U) -> BytesMut
+where
+    T: AsRef<[u8]>,
+    U: AsRef<[u8]>,
+{
+    let timeout = timeout.unwrap_or(0usize) as u32;
+    let contextb = context
+        .as_ref()
+        .map(|x| x.as_ref())
+        .unwrap_or(&super::EMPTY_U8);
+    let codeb = code.as_ref();
+    let contextblen = contextb.len() as u32;
+    let codeblen = codeb.len() as u32;
+
+    let mut buf = BytesMut::with_capacity(12usize + contextblen as usize + codeblen as usize);
+    buf.put_u32_le(timeout * 1000);
+    buf.put_u32_le(contextblen);
+    buf.put_u32_le(codeblen);
+    buf.put(&contextb[..contextblen as usize]);
+    buf.put(&codeb[..codeblen as usize]);
+    buf
+}
+
+pub fn make_v2_request<T, U>(
+    timeout: Option<usize>,
+    context: Option<T>,
+    code: U,
+    stdin: Option<&str>,
+) -> BytesMut
+where
+    T: AsRef<[u8]>,
+    U: AsRef<[u8]>,
+{
+    let mut buf = BytesMut::new();
+    buf.put(&MAGIC[..]);
+    buf.put_u32_le(LATEST_VERSION);
+    put_frame(
+        &mut buf,
+        REQ_TIMEOUT,
+        &(timeout.unwrap_or(0) as u32 * 1000).to_le_bytes(),
+    );
+    if let Some(context) = context {
+        put_frame(&mut buf, REQ_CONTEXT, context.as_ref());
+    }
+    put_frame(&mut buf, REQ_CODE, code.as_ref());
+    if let Some(stdin) = stdin {
+        put_frame(&mut buf, REQ_STDIN, stdin.as_bytes());
+    }
+    put_frame(&mut buf, REQ_END, &[]);
+    buf
+}
+
+pub fn make_v2_op_request<T>(op: Op, context: Option<T>) -> BytesMut
+where
+    T: AsRef<[u8]>,
+{
+    let mut buf = BytesMut::new();
+    buf.put(&MAGIC[..]);
+    buf.put_u32_le(LATEST_VERSION);
+    put_frame(&mut buf, REQ_OP, &(op as u32).to_le_bytes());
+    if let Some(context) = context {
+        put_frame(&mut buf, REQ_CONTEXT, context.as_ref());
+    }
+    put_frame(&mut buf, REQ_END, &[]);
+    buf
+}
+
+fn put_frame(buf: &mut BytesMut, kind: u32, data: &[u8]) {
+    buf.reserve(8 + data.len());
+    buf.put_u32_le(kind);
+    buf.put_u32_le(data.len() as u32);
+    buf.put(data);
+}
+
+async fn read_u32<R>(conn: &mut R) -> Result<u32, EvalError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut b = [0u8; 4];
+    conn.read_exact(&mut b).await.map_err(EvalError::Io)?;
+    Ok((&b[..]).get_u32_le())
+}
+
+/// Reads `len` bytes, keeping at most `keep` of them. Returns whether anything was dropped.
+async fn read_data<R>(
+    conn: &mut R,
+    len: usize,
+    keep: usize,
+    into: &mut BytesMut,
+) -> Result<bool, EvalError>
+where
+    R: AsyncRead + Unpin,
+{
+    let keep = keep.min(len);
+    let start = into.len();
+    into.resize(start + keep, 0);
+    conn.read_exact(&mut into[start..])
+        .await
+        .map_err(EvalError::Io)?;
+    let drop = (len - keep) as u64;
+    if drop > 0 {
+        let dropped = tokio::io::copy(&mut (&mut *conn).take(drop), &mut tokio::io::sink())
+            .await
+            .map_err(EvalError::Io)?;
+        if dropped < drop {
+            return Err(EvalError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+    }
+    Ok(drop > 0)
+}
+
+/// Reads the data of a frame that is not output, which must be whole.
+async fn read_frame_data<R>(conn: &mut R, kind: u32, len: usize) -> Result<BytesMut, EvalError>
+where
+    R: AsyncRead + Unpin,
+{
+    if len > MAX_FRAME_LEN {
+        return Err(EvalError::Protocol(format!(
+            "response frame type {} is too long (length {})",
+            kind, len
+        )));
+    }
+    let mut buf = BytesMut::new();
+    read_data(conn, len, len, &mut buf).await?;
+    Ok(buf)
+}
+
+pub async fn read_v1_response<R>(conn: &mut R, out: &mut Output) -> Result<(), EvalError>
+where
+    R: AsyncRead + Unpin,
+{
+    let outlen = read_u32(conn).await? as usize;
+    trace!("result length: {}", outlen);
+    let mut buf = BytesMut::new();
+    let dropped = read_data(conn, outlen, out.remaining_bytes(), &mut buf).await?;
+
+    trace!("result: {:?}", buf);
+    out.stdout(&buf);
+    if dropped {
+        out.result_mut().truncated = true;
+    }
+    Ok(())
+}
+
+pub async fn read_v2_response<R>(conn: &mut R, out: &mut Output) -> Result<(), EvalError>
+where
+    R: AsyncRead + Unpin,
+{
+    read_v2_header(conn).await?;
+    loop {
+        let (kind, len) = read_frame_header(conn).await?;
+        if kind == RESP_STDOUT || kind == RESP_STDERR {
+            // only output counts towards the output limits
+            let mut buf = BytesMut::new();
+            let dropped = read_data(conn, len, out.remaining_bytes(), &mut buf).await?;
+            if kind == RESP_STDOUT {
+                out.stdout(&buf);
+            } else {
+                out.stderr(&buf);
+            }
+            if dropped {
+                out.result_mut().truncated = true;
+            }
+            // once the output is full, further output is read and discarded, so that the value
+            // and exit status that follow it are still seen
+            continue;
+        }
+        let buf = read_frame_data(conn, kind, len).await?;
+        match kind {
+            RESP_END => break,
+            RESP_VALUE => out.result_mut().value = Some(String::from_utf8_lossy(&buf).into_owned()),
+            RESP_STATUS if buf.len() == 4 => {
+                out.result_mut().exit_code = Some((&buf[..]).get_i32_le())
+            }
+            RESP_ERROR => {
+                return Err(EvalError::Evaluator(
+                    String::from_utf8_lossy(&buf).into_owned(),
+                ))
+            }
+            _ => {
+                return Err(EvalError::Protocol(format!(
+                    "unexpected response frame type {} (length {})",
+                    kind, len
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the response to an op request, which lists contexts for `Op::List` and is otherwise
+/// empty.
+pub async fn read_v2_op_response<R>(conn: &mut R) -> Result<Vec<ContextInfo>, EvalError>
+where
+    R: AsyncRead + Unpin,
+{
+    read_v2_header(conn).await?;
+    let mut contexts = Vec::new();
+    loop {
+        let (kind, len) = read_frame_header(conn).await?;
+        let mut buf = read_frame_data(conn, kind, len).await?;
+        match kind {
+            RESP_END => break,
+            RESP_CONTEXT if buf.len() >= 8 => {
+                let memory = buf.get_i64_le();
+                contexts.push(ContextInfo {
+                    key: String::from_utf8_lossy(&buf).into_owned(),
+                    memory: u64::try_from(memory).ok(),
+                });
+            }
+            RESP_ERROR => {
+                return Err(EvalError::Evaluator(
+                    String::from_utf8_lossy(&buf).into_owned(),
+                ))
+            }
+            _ => {
+                return Err(EvalError::Protocol(format!(
+                    "unexpected response frame type {} (length {})",
+                    kind, len
+                )))
+            }
+        }
+    }
+    Ok(contexts)
+}
+
+async fn read_v2_header<R>(conn: &mut R) -> Result<(), EvalError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut magic = [0u8; 4];
+    conn.read_exact(&mut magic).await.map_err(EvalError::Io)?;
+    if &magic != MAGIC {
+        return Err(EvalError::Protocol(
+            "bad magic in response; does the evaluator speak protocol v2?".to_owned(),
+        ));
+    }
+    let version = read_u32(conn).await?;
+    if version != 2 {
+        return Err(EvalError::Protocol(format!(
+            "evaluator responded with protocol version {}",
+            version
+        )));
+    }
+    Ok(())
+}
+
+async fn read_frame_header<R>(conn: &mut R) -> Result<(u32, usize), EvalError>
+where
+    R: AsyncRead + Unpin,
+{
+    let kind = read_u32(conn).await?;
+    let len = read_u32(conn).await? as usize;
+    trace!("response frame: type {}, length {}", kind, len);
+    Ok((kind, len))
+}
+
+#[cfg(test)]
+mod test {
+    /// Builds a version 2 response made of `frames`.
+    fn response(frames: &[(u32, &[u8])]) -> bytes::BytesMut {
+        let mut resp = bytes::BytesMut::new();
+        resp.extend_from_slice(super::MAGIC);
+        resp.extend_from_slice(&2u32.to_le_bytes());
+        for &(kind, data) in frames {
+            super::put_frame(&mut resp, kind, data);
+        }
+        resp
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_response() {
+        let resp = response(&[
+            (super::RESP_STDOUT, b"hello\n"),
+            (super::RESP_STDERR, b"warning\n"),
+            (super::RESP_VALUE, b"42"),
+            (super::RESP_STATUS, &1i32.to_le_bytes()),
+            (super::RESP_END, &[]),
+        ]);
+
+        let mut out = crate::output::Output::new(None, Default::default());
+        super::read_v2_response(&mut &resp[..], &mut out)
+            .await
+            .unwrap();
+        let result = out.finish();
+        assert_eq!(result.stdout, "hello\n");
+        assert_eq!(result.stderr, "warning\n");
+        assert_eq!(result.value.as_deref(), Some("42"));
+        assert_eq!(result.exit_code, Some(1));
+        assert!(!result.truncated);
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_response_exact_fill() {
+        let resp = response(&[
+            (super::RESP_STDOUT, b"hello\n"),
+            (super::RESP_VALUE, b"a value longer than the limit"),
+            (super::RESP_STATUS, &1i32.to_le_bytes()),
+            (super::RESP_END, &[]),
+        ]);
+
+        // the output fills the limit exactly, which does not cut the frames that follow
+        let limits = crate::output::OutputLimits {
+            bytes: Some(6),
+            lines: None,
+        };
+        let mut out = crate::output::Output::new(None, limits);
+        super::read_v2_response(&mut &resp[..], &mut out)
+            .await
+            .unwrap();
+        let result = out.finish();
+        assert_eq!(result.stdout, "hello\n");
+        assert_eq!(
+            result.value.as_deref(),
+            Some("a value longer than the limit")
+        );
+        assert_eq!(result.exit_code, Some(1));
+        assert!(!result.truncated);
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_response_truncated() {
+        let resp = response(&[
+            (super::RESP_STDOUT, b"hello\n"),
+            (super::RESP_STDERR, b"warning\n"),
+            (super::RESP_STATUS, &1i32.to_le_bytes()),
+            (super::RESP_END, &[]),
+        ]);
+
+        // output past the limit is discarded, but the exit status after it is kept
+        let limits = crate::output::OutputLimits {
+            bytes: Some(3),
+            lines: None,
+        };
+        let mut out = crate::output::Output::new(None, limits);
+        super::read_v2_response(&mut &resp[..], &mut out)
+            .await
+            .unwrap();
+        let result = out.finish();
+        assert_eq!(result.stdout, "hel");
+        assert_eq!(result.stderr, "");
+        assert_eq!(result.exit_code, Some(1));
+        assert!(result.truncated);
+    }
+
+    #[test]
+    fn test_make_v2_op_request() {
+        let mut expected = bytes::BytesMut::new();
+        expected.extend_from_slice(super::MAGIC);
+        expected.extend_from_slice(&2u32.to_le_bytes());
+        super::put_frame(&mut expected, super::REQ_OP, &2u32.to_le_bytes());
+        super::put_frame(&mut expected, super::REQ_CONTEXT, b"chat");
+        super::put_frame(&mut expected, super::REQ_END, &[]);
+        assert_eq!(
+            super::make_v2_op_request(super::Op::Drop, Some("chat")),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_op_response() {
+        let mut chat = (1024i64).to_le_bytes().to_vec();
+        chat.extend_from_slice(b"chat");
+        let mut other = (-1i64).to_le_bytes().to_vec();
+        other.extend_from_slice(b"other");
+        let resp = response(&[
+            (super::RESP_CONTEXT, &chat),
+            (super::RESP_CONTEXT, &other),
+            (super::RESP_END, &[]),
+        ]);
+
+        let contexts = super::read_v2_op_response(&mut &resp[..]).await.unwrap();
+        assert_eq!(
+            contexts,
+            vec![
+                crate::ContextInfo {
+                    key: "chat".to_owned(),
+                    memory: Some(1024),
+                },
+                crate::ContextInfo {
+                    key: "other".to_owned(),
+                    memory: None,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_op_response_too_long() {
+        // a frame too long to be a context is refused before it is read
+        let mut resp = response(&[]);
+        resp.extend_from_slice(&super::RESP_CONTEXT.to_le_bytes());
+        resp.extend_from_slice(&u32::MAX.to_le_bytes());
+        match super::read_v2_op_response(&mut &resp[..]).await {
+            Err(crate::EvalError::Protocol(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
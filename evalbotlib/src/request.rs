@@ -0,0 +1,114 @@
+use std::num::NonZeroUsize;
+
+/// How long an evaluation may run.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum TimeLimit {
+    /// The language's configured timeout.
+    #[default]
+    Default,
+    Unlimited,
+    Seconds(NonZeroUsize),
+}
+
+/// Code to evaluate, along with everything about how to evaluate it.
+///
+/// ```
+/// # use std::num::NonZeroUsize;
+/// # use evalbotlib::{EvalRequest, TimeLimit};
+/// let request = EvalRequest::new("println!(\"hi\")")
+///     .time_limit(TimeLimit::Seconds(NonZeroUsize::new(5).unwrap()))
+///     .context("tg1234")
+///     .max_output_lines(10);
+/// ```
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct EvalRequest {
+    pub(crate) code: String,
+    pub(crate) template: Option<String>,
+    pub(crate) time_limit: TimeLimit,
+    pub(crate) context: Option<String>,
+    pub(crate) stdin: Option<String>,
+    pub(crate) args: Vec<String>,
+    pub(crate) env: Vec<(String, String)>,
+    pub(crate) max_output_bytes: Option<usize>,
+    pub(crate) max_output_lines: Option<usize>,
+    pub(crate) requester: Option<String>,
+    pub(crate) priority: i32,
+}
+
+impl EvalRequest {
+    pub fn new<T: Into<String>>(code: T) -> Self {
+        EvalRequest {
+            code: code.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The language's template to wrap the code in, instead of its default.
+    pub fn template<T: Into<String>>(mut self, template: T) -> Self {
+        self.template = Some(template.into());
+        self
+    }
+
+    pub fn time_limit(mut self, time_limit: TimeLimit) -> Self {
+        self.time_limit = time_limit;
+        self
+    }
+
+    /// The key of the context to evaluate in, for persistent evaluators.
+    pub fn context<T: Into<String>>(mut self, context: T) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+
+    /// Input for the evaluated program.
+    pub fn stdin<T: Into<String>>(mut self, stdin: T) -> Self {
+        self.stdin = Some(stdin.into());
+        self
+    }
+
+    /// Arguments for the evaluated program.
+    pub fn args<I>(mut self, args: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// An environment variable for the evaluated program. Only languages run by `cmdline`
+    /// support this.
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Overrides the language's output size limit; 0 for no limit.
+    pub fn max_output_bytes(mut self, bytes: usize) -> Self {
+        self.max_output_bytes = Some(bytes);
+        self
+    }
+
+    /// Overrides the language's output line limit; 0 for no limit.
+    pub fn max_output_lines(mut self, lines: usize) -> Self {
+        self.max_output_lines = Some(lines);
+        self
+    }
+
+    /// Who the evaluation is for, for logging.
+    pub fn requester<T: Into<String>>(mut self, requester: T) -> Self {
+        self.requester = Some(requester.into());
+        self
+    }
+
+    /// Requests with a higher priority go first when evaluations have to queue. The default is
+    /// 0.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+}
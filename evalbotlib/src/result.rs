@@ -0,0 +1,209 @@
+use std::fmt;
+use std::time::Duration;
+
+/// The outcome of a single evaluation.
+///
+/// Persistent evaluators speaking protocol v1 only return a single response, which is placed in
+/// `stdout`. Persistent evaluators never report a signal.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct EvalResult {
+    pub stdout: String,
+    pub stderr: String,
+    /// The value of the evaluated expression, if the evaluator reports it separately.
+    pub value: Option<String>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    /// The syscall the sandbox killed the program for making, if that is how it ended.
+    pub bad_syscall: Option<BadSyscall>,
+    /// Whether the program was killed for exceeding its memory limit, if it has one.
+    pub oom_killed: bool,
+    /// The resources the program used, if the backend measures them.
+    pub usage: Option<ResourceUsage>,
+    pub wall_time: Duration,
+    pub truncated: bool,
+}
+
+/// A syscall that is not in a sandboxed program's list of syscalls it may make.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BadSyscall {
+    pub number: u32,
+    /// The syscall's name, if it is in the host's syscall table.
+    pub name: Option<&'static str>,
+}
+
+/// The resources a program, and the processes it waited for, used.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ResourceUsage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// The peak resident set size of the largest of those processes, in bytes.
+    pub peak_memory: u64,
+}
+
+/// An event in a streamed evaluation.
+#[derive(Clone, PartialEq, Debug)]
+pub enum EvalEvent {
+    /// Output the program wrote to stdout.
+    Stdout(String),
+    /// Output the program wrote to stderr.
+    Stderr(String),
+    /// The evaluation has finished. This is always the last event.
+    Finished(EvalResult),
+}
+
+impl EvalResult {
+    pub fn success(&self) -> bool {
+        self.exit_code.is_none_or(|c| c == 0) && self.signal.is_none()
+    }
+
+    /// A human-readable description of how the program terminated, if it did not succeed.
+    pub fn status_message(&self) -> Option<String> {
+        if self.oom_killed && self.signal == Some(libc::SIGKILL) {
+            Some("memory limit exceeded".to_owned())
+        } else if let Some(sig) = self.signal {
+            let mut message = format!("signalled with {} ({})", strsig(sig), strsigabbrev(sig));
+            if let Some(ref syscall) = self.bad_syscall {
+                message.push_str(&format!(" for making syscall {}", syscall));
+            }
+            Some(message)
+        } else {
+            match self.exit_code {
+                Some(0) | None => None,
+                Some(code) if self.oom_killed => Some(format!(
+                    "exited with status {} after exceeding the memory limit",
+                    code
+                )),
+                Some(code) => Some(format!("exited with status {}", code)),
+            }
+        }
+    }
+}
+
+impl fmt::Display for EvalResult {
+    /// Formats the result the way a terminal would show it: stderr, then stdout, then the value
+    /// and the exit status if it was not successful.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.stderr)?;
+        f.write_str(&self.stdout)?;
+        let mut last = if self.stdout.is_empty() {
+            &self.stderr
+        } else {
+            &self.stdout
+        };
+        if let Some(ref value) = self.value {
+            if !last.is_empty() && !last.ends_with('\n') {
+                f.write_str("\n")?;
+            }
+            f.write_str(value)?;
+            last = value;
+        }
+        if let Some(status) = self.status_message() {
+            if !last.ends_with('\n') {
+                f.write_str("\n")?;
+            }
+            writeln!(f, "{}", status)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for BadSyscall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "{} ({})", name, self.number),
+            None => write!(f, "{}", self.number),
+        }
+    }
+}
+
+impl ResourceUsage {
+    pub fn cpu_time(&self) -> Duration {
+        self.user_time + self.system_time
+    }
+}
+
+impl fmt::Display for ResourceUsage {
+    /// Formats the usage briefly, as in "used 12 ms, 3.1 MB".
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "used {} ms, {:.1} MB",
+            self.cpu_time().as_millis(),
+            self.peak_memory as f64 / 1_000_000.0
+        )
+    }
+}
+
+fn strsig(sig: i32) -> &'static str {
+    match sig {
+        1 => "Hangup",
+        2 => "Interrupt",
+        3 => "Quit",
+        4 => "Illegal instruction",
+        5 => "Trace/breakpoint trap",
+        6 => "Aborted",
+        7 => "Bus error",
+        8 => "Floating point exception",
+        9 => "Killed",
+        10 => "User defined signal 1",
+        11 => "Segmentation fault",
+        12 => "User defined signal 2",
+        13 => "Broken pipe",
+        14 => "Alarm clock",
+        15 => "Terminated",
+        16 => "Stack fault",
+        17 => "Child exited",
+        18 => "Continued",
+        19 => "Stopped (signal)",
+        20 => "Stopped",
+        21 => "Stopped (tty input)",
+        22 => "Stopped (tty output)",
+        23 => "Urgent I/O condition",
+        24 => "CPU time limit exceeded",
+        25 => "File size limit exceeded",
+        26 => "Virtual timer expired",
+        27 => "Profiling timer expired",
+        28 => "Window changed",
+        29 => "I/O possible",
+        30 => "Power failure",
+        31 => "Bad system call",
+        _ => "Unknown signal",
+    }
+}
+
+fn strsigabbrev(sig: i32) -> &'static str {
+    match sig {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        5 => "SIGTRAP",
+        6 => "SIGABRT",
+        7 => "SIGBUS",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        10 => "SIGUSR1",
+        11 => "SIGSEGV",
+        12 => "SIGUSR2",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        16 => "SIGSTKFLT",
+        17 => "SIGCHLD",
+        18 => "SIGCONT",
+        19 => "SIGSTOP",
+        20 => "SIGTSTP",
+        21 => "SIGTTIN",
+        22 => "SIGTTOU",
+        23 => "SIGURG",
+        24 => "SIGXCPU",
+        25 => "SIGXFSZ",
+        26 => "SIGVTALRM",
+        27 => "SIGPROF",
+        28 => "SIGWINCH",
+        29 => "SIGPOLL",
+        30 => "SIGPWR",
+        31 => "SIGSYS",
+        _ => "(unknown)",
+    }
+}
@@ -0,0 +1,590 @@
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+use tokio::process::Command;
+
+use crate::monitor::{self, Monitor};
+use crate::syscalls::{self, SyscallList};
+use crate::SandboxCfg;
+
+const DEFAULT_HOSTNAME: &str = "sandbox";
+const DEFAULT_DEVICES: &[&str] = &["/dev/null", "/dev/zero", "/dev/urandom"];
+/// Size of the sandbox's `/tmp` in MiB.
+const DEFAULT_TMP_SIZE: u64 = 64;
+/// The user and group ID the program runs as by default.
+const DEFAULT_ID: u32 = 1000;
+/// Why the program may not run as root: it could remount the read-only root read-write.
+pub const ROOT_UID: &str = "uid 0 would let the program remount the root read-write";
+/// The environment of programs in the sandbox.
+pub const ENV: &[(&str, &str)] = &[("PATH", "/usr/local/bin:/usr/bin:/bin"), ("HOME", "/tmp")];
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+pub const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+/// Follow the tracee's children, stop it for syscalls its filter says to trace, and kill it if
+/// the tracer goes away.
+pub const TRACE_OPTIONS: libc::c_int = libc::PTRACE_O_EXITKILL
+    | libc::PTRACE_O_TRACESECCOMP
+    | libc::PTRACE_O_TRACEFORK
+    | libc::PTRACE_O_TRACEVFORK
+    | libc::PTRACE_O_TRACECLONE
+    | libc::PTRACE_O_TRACEEXEC;
+
+/// Runs programs the way the `playpen` wrapper used to: in new user, mount, PID, UTS, IPC and
+/// network namespaces, with `root` mounted read-only as `/`, a fresh `/tmp`, `/proc` and `/dev`,
+/// and a seccomp filter that kills the program if it makes a syscall that is not in `syscalls`.
+///
+/// If `cgroup` names a cgroup v2 directory the bot may create cgroups in, each program gets a
+/// cgroup of its own with the configured memory, task and CPU limits.
+#[derive(PartialEq, Debug)]
+pub struct Sandbox {
+    cfg: SandboxCfg,
+    /// The numbers of the syscalls the program may make, if they are restricted.
+    syscalls: Option<Vec<u32>>,
+}
+
+/// A cgroup for a single program, killed and removed when dropped.
+#[derive(Debug)]
+pub struct Cgroup(PathBuf);
+
+/// Everything the child needs to enter the sandbox, prepared beforehand so that it does not have
+/// to allocate between `fork` and `exec`.
+pub struct Setup {
+    cgroup_procs: Option<File>,
+    report: Option<File>,
+    network: bool,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    root: CString,
+    root_flags: libc::c_ulong,
+    tmp: Option<(CString, CString)>,
+    proc_: Option<CString>,
+    dev: Option<CString>,
+    devices: Vec<(CString, CString)>,
+    hostname: Vec<u8>,
+    cwd: CString,
+    filter: Option<Vec<libc::sock_filter>>,
+    /// The filter to install instead if the program cannot be traced.
+    untraced_filter: Option<Vec<libc::sock_filter>>,
+}
+
+impl Sandbox {
+    pub fn new(cfg: SandboxCfg) -> Result<Self, String> {
+        if cfg.uid == Some(0) {
+            return Err(ROOT_UID.to_owned());
+        }
+        let syscalls = match cfg.syscalls {
+            Some(ref path) => Some(SyscallList::read(path)?.numbers()),
+            None => None,
+        };
+        Ok(Sandbox { cfg, syscalls })
+    }
+
+    /// Makes `cmd` run in the sandbox, under a monitor that must be kept until the program has
+    /// exited.
+    pub fn apply(&self, cmd: &mut Command) -> io::Result<Monitor> {
+        let (mut monitor, report) = Monitor::new()?;
+        let filter = self
+            .syscalls
+            .as_ref()
+            .map(|syscalls| seccomp_filter(syscalls, SECCOMP_RET_TRACE));
+        let (setup, cgroup) = self.prepare(filter, Some(report))?;
+        monitor.set_cgroup(cgroup);
+        cmd.env_clear().envs(ENV.iter().copied());
+        unsafe {
+            cmd.pre_exec(move || setup.enter());
+        }
+        Ok(monitor)
+    }
+
+    /// Prepares to run a program in the sandbox with `filter` instead of the configured one.
+    /// With `report`, the program is monitored as described in `monitor::wait_and_exit`, and
+    /// traced if it has a filter.
+    pub fn prepare(
+        &self,
+        filter: Option<Vec<libc::sock_filter>>,
+        report: Option<File>,
+    ) -> io::Result<(Setup, Option<Cgroup>)> {
+        let cgroup = match self.cfg.cgroup {
+            Some(ref parent) => Some(Cgroup::create(Path::new(parent), &self.cfg)?),
+            None => None,
+        };
+        let setup = Setup::new(&self.cfg, filter, report, cgroup.as_ref())?;
+        Ok((setup, cgroup))
+    }
+}
+
+impl Cgroup {
+    fn create(parent: &Path, cfg: &SandboxCfg) -> io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = parent.join(format!(
+            "evalbot.{}.{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&path)?;
+        let cgroup = Cgroup(path);
+        if let Some(memory) = cfg.memory_limit {
+            cgroup.write("memory.max", &(memory * 1024 * 1024).to_string())?;
+            // not every kernel has swap accounting
+            let _ = cgroup.write("memory.swap.max", "0");
+        }
+        if let Some(pids) = cfg.pids_limit {
+            cgroup.write("pids.max", &pids.to_string())?;
+        }
+        if let Some(cpus) = cfg.cpu_limit {
+            const PERIOD: u64 = 100_000;
+            let quota = (cpus * PERIOD as f64) as u64;
+            cgroup.write("cpu.max", &format!("{} {}", quota, PERIOD))?;
+        }
+        Ok(cgroup)
+    }
+
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        let path = self.0.join(file);
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(value.as_bytes()))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// The value of `key` in a flat keyed file like `memory.events`, if the cgroup has it.
+    pub fn stat(&self, file: &str, key: &str) -> Option<u64> {
+        stat_field(&fs::read_to_string(self.0.join(file)).ok()?, key)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // anything the program left behind would keep the cgroup from being removed
+        let _ = self.write("cgroup.kill", "1");
+        match fs::remove_dir(&self.0) {
+            // killed processes take a moment to go away, which is no reason to hold up a worker
+            Err(ref e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                let path = mem::take(&mut self.0);
+                match tokio::runtime::Handle::try_current() {
+                    Ok(handle) => {
+                        handle.spawn_blocking(move || remove_cgroup(&path));
+                    }
+                    Err(_) => remove_cgroup(&path),
+                }
+            }
+            Err(e) => warn!("failed to remove cgroup {}: {}", self.0.display(), e),
+            Ok(()) => {}
+        }
+    }
+}
+
+/// Removes a cgroup whose processes were killed, once they are gone.
+fn remove_cgroup(path: &Path) {
+    let mut removed = fs::remove_dir(path);
+    for _ in 0..100 {
+        match removed {
+            Err(ref e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                thread::sleep(Duration::from_millis(1));
+                removed = fs::remove_dir(path);
+            }
+            _ => break,
+        }
+    }
+    if let Err(e) = removed {
+        warn!("failed to remove cgroup {}: {}", path.display(), e);
+    }
+}
+
+impl Setup {
+    fn new(
+        cfg: &SandboxCfg,
+        filter: Option<Vec<libc::sock_filter>>,
+        report: Option<File>,
+        cgroup: Option<&Cgroup>,
+    ) -> io::Result<Self> {
+        // paths only enter a mount on top of / by walking through a component, so "/.." leads
+        // into the root's bind mount where "/" would lead to the root under it
+        let root = match fs::canonicalize(&cfg.root)? {
+            ref root if root == Path::new("/") => Path::new("/.."),
+            _ => Path::new(&cfg.root),
+        };
+        let root_flags = mount_flags(root)?;
+        let existing_dir = |dir: &str| -> io::Result<Option<CString>> {
+            let path = root.join(dir);
+            if path.is_dir() {
+                Ok(Some(cstring(path.as_os_str().as_bytes())?))
+            } else {
+                Ok(None)
+            }
+        };
+        let tmp = match existing_dir("tmp")? {
+            Some(tmp) => {
+                let size = cfg.tmp_size.unwrap_or(DEFAULT_TMP_SIZE);
+                Some((
+                    tmp,
+                    cstring(format!("size={}m,mode=1777", size).as_bytes())?,
+                ))
+            }
+            None => None,
+        };
+        let devices = match cfg.devices {
+            Some(ref devices) => devices.iter().map(String::as_str).collect(),
+            None => DEFAULT_DEVICES.to_vec(),
+        };
+        let dev = if devices.is_empty() {
+            None
+        } else {
+            existing_dir("dev")?
+        };
+        let devices = match dev {
+            Some(_) => devices
+                .iter()
+                .map(|d| {
+                    let name = Path::new(d).file_name().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, format!("bad device {}", d))
+                    })?;
+                    let target = root.join("dev").join(name);
+                    Ok((
+                        cstring(d.as_bytes())?,
+                        cstring(target.as_os_str().as_bytes())?,
+                    ))
+                })
+                .collect::<io::Result<_>>()?,
+            None => Vec::new(),
+        };
+        let id = cfg.uid.unwrap_or(DEFAULT_ID);
+        let untraced_filter = match (&filter, &report) {
+            (Some(filter), Some(_)) => Some(untraced(filter)),
+            _ => None,
+        };
+        Ok(Setup {
+            cgroup_procs: match cgroup {
+                Some(cgroup) => Some(
+                    OpenOptions::new()
+                        .write(true)
+                        .open(cgroup.0.join("cgroup.procs"))?,
+                ),
+                None => None,
+            },
+            report,
+            network: cfg.network.unwrap_or(false),
+            uid_map: format!("{} {} 1", id, unsafe { libc::getuid() }).into_bytes(),
+            gid_map: format!("{} {} 1", id, unsafe { libc::getgid() }).into_bytes(),
+            root: cstring(root.as_os_str().as_bytes())?,
+            root_flags,
+            cwd: cstring(if tmp.is_some() { b"/tmp" } else { b"/" })?,
+            tmp,
+            proc_: existing_dir("proc")?,
+            dev,
+            devices,
+            hostname: cfg
+                .hostname
+                .as_deref()
+                .unwrap_or(DEFAULT_HOSTNAME)
+                .as_bytes()
+                .to_vec(),
+            filter,
+            untraced_filter,
+        })
+    }
+
+    /// Runs in the child, after `fork`. Returns in a new process in the sandbox, which then
+    /// execs the program.
+    pub fn enter(&self) -> io::Result<()> {
+        unsafe {
+            if let Some(ref procs) = self.cgroup_procs {
+                check(libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) as libc::c_int)?;
+            }
+            let mut namespaces = libc::CLONE_NEWUSER
+                | libc::CLONE_NEWNS
+                | libc::CLONE_NEWPID
+                | libc::CLONE_NEWUTS
+                | libc::CLONE_NEWIPC;
+            if !self.network {
+                namespaces |= libc::CLONE_NEWNET;
+            }
+            check(libc::unshare(namespaces))?;
+            write_file(b"/proc/self/setgroups\0", b"deny")?;
+            write_file(b"/proc/self/uid_map\0", &self.uid_map)?;
+            write_file(b"/proc/self/gid_map\0", &self.gid_map)?;
+
+            // only children join the new PID namespace
+            match check(libc::fork())? {
+                0 => {}
+                pid => monitor::wait_and_exit(
+                    pid,
+                    self.report.as_ref().map(File::as_raw_fd),
+                    self.traced(),
+                ),
+            }
+            // if the bot kills the process it started, this one goes with it
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0))?;
+
+            let null = std::ptr::null::<libc::c_char>();
+            check(libc::mount(
+                null,
+                b"/\0".as_ptr().cast(),
+                null,
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            ))?;
+            check(libc::mount(
+                self.root.as_ptr(),
+                self.root.as_ptr(),
+                null,
+                libc::MS_BIND | libc::MS_REC,
+                std::ptr::null(),
+            ))?;
+            if let Some((ref tmp, ref options)) = self.tmp {
+                check(libc::mount(
+                    b"tmpfs\0".as_ptr().cast(),
+                    tmp.as_ptr(),
+                    b"tmpfs\0".as_ptr().cast(),
+                    libc::MS_NOSUID | libc::MS_NODEV,
+                    options.as_ptr().cast(),
+                ))?;
+            }
+            if let Some(ref proc_) = self.proc_ {
+                check(libc::mount(
+                    b"proc\0".as_ptr().cast(),
+                    proc_.as_ptr(),
+                    b"proc\0".as_ptr().cast(),
+                    libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                    std::ptr::null(),
+                ))?;
+            }
+            if let Some(ref dev) = self.dev {
+                check(libc::mount(
+                    b"tmpfs\0".as_ptr().cast(),
+                    dev.as_ptr(),
+                    b"tmpfs\0".as_ptr().cast(),
+                    libc::MS_NOSUID | libc::MS_NOEXEC,
+                    b"mode=755\0".as_ptr().cast(),
+                ))?;
+                for (device, target) in &self.devices {
+                    let fd = check(libc::open(
+                        target.as_ptr(),
+                        libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                        0o644,
+                    ))?;
+                    libc::close(fd);
+                    check(libc::mount(
+                        device.as_ptr(),
+                        target.as_ptr(),
+                        null,
+                        libc::MS_BIND,
+                        std::ptr::null(),
+                    ))?;
+                }
+            }
+            // the flags the root is already mounted with cannot be cleared in a user namespace
+            check(libc::mount(
+                null,
+                self.root.as_ptr(),
+                null,
+                libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | self.root_flags,
+                std::ptr::null(),
+            ))?;
+            check(libc::sethostname(
+                self.hostname.as_ptr().cast(),
+                self.hostname.len(),
+            ))?;
+            // unlike chroot, this leaves nothing of the old root to escape to
+            check(libc::chdir(self.root.as_ptr()))?;
+            check(
+                libc::syscall(libc::SYS_pivot_root, b".\0".as_ptr(), b".\0".as_ptr())
+                    as libc::c_int,
+            )?;
+            check(libc::umount2(b".\0".as_ptr().cast(), libc::MNT_DETACH))?;
+            check(libc::chdir(self.cwd.as_ptr()))?;
+
+            if let Some(ref filter) = self.filter {
+                match self.untraced_filter {
+                    // where ptrace is restricted, bad syscalls kill the program without a report
+                    Some(ref untraced_filter)
+                        if libc::ptrace(
+                            libc::PTRACE_TRACEME,
+                            0,
+                            std::ptr::null_mut::<libc::c_void>(),
+                            std::ptr::null_mut::<libc::c_void>(),
+                        ) == -1 =>
+                    {
+                        install_filter(untraced_filter)?;
+                    }
+                    Some(_) => {
+                        check(libc::raise(libc::SIGSTOP))?;
+                        install_filter(filter)?;
+                    }
+                    None => install_filter(filter)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the monitor traces the program to find out which syscall it was killed for.
+    fn traced(&self) -> bool {
+        self.report.is_some() && self.filter.is_some()
+    }
+}
+
+unsafe fn write_file(path: &[u8], data: &[u8]) -> io::Result<()> {
+    let fd = check(libc::open(
+        path.as_ptr().cast(),
+        libc::O_WRONLY | libc::O_CLOEXEC,
+    ))?;
+    let written = libc::write(fd, data.as_ptr().cast(), data.len());
+    libc::close(fd);
+    if written == data.len() as isize {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Applies a seccomp filter to the calling thread and the programs it execs. Safe to call between
+/// `fork` and `exec`.
+pub fn install_filter(filter: &[libc::sock_filter]) -> io::Result<()> {
+    let prog = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    unsafe {
+        check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+        check(libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &prog as *const libc::sock_fprog,
+        ))?;
+    }
+    Ok(())
+}
+
+pub fn ptrace(request: libc::c_uint, pid: libc::pid_t, data: usize) -> io::Result<()> {
+    let res = unsafe {
+        libc::ptrace(
+            request,
+            pid,
+            std::ptr::null_mut::<libc::c_void>(),
+            data as *mut libc::c_void,
+        )
+    };
+    check(res as libc::c_int).map(|_| ())
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn syscall_number(pid: libc::pid_t) -> io::Result<u32> {
+    let mut regs = unsafe { std::mem::zeroed::<libc::user_regs_struct>() };
+    ptrace(
+        libc::PTRACE_GETREGS,
+        pid,
+        &mut regs as *mut libc::user_regs_struct as usize,
+    )?;
+    Ok(regs.orig_rax as u32)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn syscall_number(_pid: libc::pid_t) -> io::Result<u32> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+pub fn check(res: libc::c_int) -> io::Result<libc::c_int> {
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+pub fn cstring(s: &[u8]) -> io::Result<CString> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// `filter`, which traces bad syscalls, changed to kill the program for them instead.
+fn untraced(filter: &[libc::sock_filter]) -> Vec<libc::sock_filter> {
+    let mut filter = filter.to_vec();
+    if let Some(otherwise) = filter.last_mut() {
+        otherwise.k = SECCOMP_RET_KILL_PROCESS;
+    }
+    filter
+}
+
+/// Finds `key` in the contents of a flat keyed file, which has a key and a value on each line.
+fn stat_field(contents: &str, key: &str) -> Option<u64> {
+    contents.lines().find_map(|line| {
+        let (k, v) = line.split_once(' ')?;
+        if k == key {
+            v.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// The flags of the mount `path` is on that a bind mount of it has to keep.
+fn mount_flags(path: &Path) -> io::Result<libc::c_ulong> {
+    let path = cstring(path.as_os_str().as_bytes())?;
+    let mut stat = unsafe { mem::zeroed::<libc::statvfs>() };
+    check(unsafe { libc::statvfs(path.as_ptr(), &mut stat) })?;
+    let mut flags = 0;
+    for &(st, ms) in &[
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & st != 0 {
+            flags |= ms;
+        }
+    }
+    Ok(flags)
+}
+
+/// A seccomp filter that allows the given syscalls and returns `otherwise` for any other, such as
+/// `SECCOMP_RET_KILL_PROCESS`. Its last instruction returns `otherwise`.
+pub fn seccomp_filter(syscalls: &[u32], otherwise: u32) -> Vec<libc::sock_filter> {
+    const LD_ABS: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    const JEQ: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+    const RET: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+    let op = |code, jt, jf, k| libc::sock_filter { code, jt, jf, k };
+    // offsets into struct seccomp_data
+    let (nr, arch) = (0, 4);
+
+    let mut filter = vec![
+        op(LD_ABS, 0, 0, arch),
+        op(JEQ, 1, 0, syscalls::AUDIT_ARCH.unwrap_or(0)),
+        op(RET, 0, 0, SECCOMP_RET_KILL_PROCESS),
+        op(LD_ABS, 0, 0, nr),
+    ];
+    for &syscall in syscalls {
+        filter.push(op(JEQ, 0, 1, syscall));
+        filter.push(op(RET, 0, 0, SECCOMP_RET_ALLOW));
+    }
+    filter.push(op(RET, 0, 0, otherwise));
+    filter
+}
+
+#[cfg(test)]
+mod test {
+    use super::stat_field;
+
+    #[test]
+    fn test_stat_field() {
+        let events = "low 0\nhigh 0\nmax 12\noom 1\noom_kill 1\noom_group_kill 0\n";
+        assert_eq!(stat_field(events, "oom_kill"), Some(1));
+        assert_eq!(stat_field(events, "max"), Some(12));
+        assert_eq!(stat_field(events, "oom_kil"), None);
+        assert_eq!(stat_field("", "oom_kill"), None);
+    }
+}
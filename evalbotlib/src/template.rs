@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+/// Code to wrap around the code being evaluated.
+///
+/// `{CODE}` stands for the code, `{LANG}` for the language's name, and any other `{NAME}` for the
+/// language's variable of that name. Only upper-case names are placeholders, so that braces in the
+/// wrapped language can be written as usual.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Template(Vec<Segment>);
+
+#[derive(Clone, PartialEq, Debug)]
+enum Segment {
+    Text(String),
+    Code,
+    Lang,
+    Var(String),
+}
+
+impl Template {
+    pub fn parse(template: &str, vars: &HashMap<String, String>) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            text.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let name = match placeholder(rest) {
+                Some(name) => name,
+                None => {
+                    text.push('{');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+            if !text.is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+            segments.push(match name {
+                "CODE" => Segment::Code,
+                "LANG" => Segment::Lang,
+                _ if vars.contains_key(name) => Segment::Var(name.to_owned()),
+                _ => return Err(format!("unknown placeholder {{{}}}", name)),
+            });
+            rest = &rest[name.len() + 2..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        if !segments.contains(&Segment::Code) {
+            return Err("no {CODE} placeholder".to_owned());
+        }
+        Ok(Template(segments))
+    }
+
+    /// The template `code_before` and `code_after` amount to. Neither is searched for
+    /// placeholders.
+    pub fn around(before: Option<&str>, after: Option<&str>) -> Self {
+        let mut segments = Vec::new();
+        segments.extend(before.map(|b| Segment::Text(b.to_owned())));
+        segments.push(Segment::Code);
+        segments.extend(after.map(|a| Segment::Text(a.to_owned())));
+        Template(segments)
+    }
+
+    pub fn render(&self, code: &str, lang: &str, vars: &HashMap<String, String>) -> String {
+        let mut r = String::with_capacity(code.len());
+        for segment in &self.0 {
+            r.push_str(match segment {
+                Segment::Text(ref text) => text,
+                Segment::Code => code,
+                Segment::Lang => lang,
+                Segment::Var(ref name) => vars.get(name).map_or("", |v| v.as_str()),
+            });
+        }
+        r
+    }
+}
+
+/// Picks how to wrap code that matches a pattern: in `template`, or not at all if it is `None`.
+#[derive(Clone, Debug)]
+pub struct WrapRule {
+    matches: Regex,
+    template: Option<String>,
+}
+
+impl WrapRule {
+    pub fn new(matches: &str, template: Option<String>) -> Result<Self, String> {
+        Ok(WrapRule {
+            matches: Regex::new(matches).map_err(|e| e.to_string())?,
+            template,
+        })
+    }
+
+    pub fn matches(&self, code: &str) -> bool {
+        self.matches.is_match(code)
+    }
+
+    pub fn template(&self) -> Option<&str> {
+        self.template.as_deref()
+    }
+}
+
+impl PartialEq for WrapRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.matches.as_str() == other.matches.as_str() && self.template == other.template
+    }
+}
+
+/// Returns the name in a placeholder at the start of `s`.
+fn placeholder(s: &str) -> Option<&str> {
+    let end = s.find('}')?;
+    let name = &s[1..end];
+    if is_placeholder(name) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Whether `name` in braces would be a placeholder rather than text.
+pub fn is_placeholder(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_uppercase())
+        && chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{Template, WrapRule};
+
+    #[test]
+    fn test_template() {
+        let mut vars = HashMap::new();
+        vars.insert("EDITION".to_owned(), "2018".to_owned());
+        let template =
+            Template::parse("// {LANG} {EDITION}\nfn main() { show({CODE}) }", &vars).unwrap();
+        assert_eq!(
+            template.render("1 + 1", "rs", &vars),
+            "// rs 2018\nfn main() { show(1 + 1) }"
+        );
+
+        assert!(Template::parse("{CODE} {MISSING}", &vars).is_err());
+        assert!(Template::parse("fn main() {}", &vars).is_err());
+        assert_eq!(
+            Template::around(Some("{X}"), None).render("y", "rs", &vars),
+            "{X}y"
+        );
+    }
+
+    #[test]
+    fn test_wrap_rule() {
+        let rule = WrapRule::new(r"\bfn\s+main\s*\(", Some("program".to_owned())).unwrap();
+        assert!(rule.matches("fn main() {}"));
+        assert!(rule.matches("use std::io;\nfn  main ( ) {}"));
+        assert!(!rule.matches("fn mainly() {}"));
+        assert_eq!(rule.template(), Some("program"));
+        assert!(WrapRule::new("(", None).is_err());
+    }
+}
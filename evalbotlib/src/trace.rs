@@ -0,0 +1,231 @@
+//! Runs programs under ptrace to find out which syscalls they make.
+
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use crate::sandbox::{
+    self, check, cstring, ptrace, syscall_number, Sandbox, Setup, SECCOMP_RET_TRACE, TRACE_OPTIONS,
+};
+use crate::syscalls;
+
+/// Runs `args` with `env` added to its environment and `input` on stdin, in `sandbox` if there is
+/// one, and returns the numbers of the syscalls the program made, along with how it exited.
+///
+/// Only syscalls made after the program is started count, not those made to set up the sandbox.
+/// The program's output goes to stderr. This waits for any child of the calling process, so it
+/// must not be used where other code waits for children, like in the bot's runtime.
+///
+/// If the program is still running after `timeout`, it is killed along with everything it
+/// started, and this fails with `io::ErrorKind::TimedOut`.
+pub fn trace(
+    args: &[String],
+    env: &[(String, String)],
+    input: &str,
+    sandbox: Option<&Sandbox>,
+    timeout: Option<Duration>,
+) -> io::Result<(BTreeSet<u32>, ExitStatus)> {
+    if syscalls::AUDIT_ARCH.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "tracing syscalls is only supported on x86_64",
+        ));
+    }
+    let argv = args
+        .iter()
+        .map(|a| cstring(a.as_bytes()))
+        .collect::<io::Result<Vec<_>>>()?;
+    if argv.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty cmdline"));
+    }
+    let mut argv_ptrs = argv.iter().map(|a| a.as_ptr()).collect::<Vec<_>>();
+    argv_ptrs.push(ptr::null());
+    // every syscall stops the program for the tracer
+    let filter = sandbox::seccomp_filter(&[], SECCOMP_RET_TRACE);
+    let (setup, _cgroup) = match sandbox {
+        Some(sandbox) => {
+            let (setup, cgroup) = sandbox.prepare(Some(filter.clone()), None)?;
+            (Some(setup), cgroup)
+        }
+        None => (None, None),
+    };
+    // programs in the sandbox get its environment, others that of the caller
+    let base = match sandbox {
+        Some(_) => sandbox::ENV
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect(),
+        None => std::env::vars().collect::<Vec<_>>(),
+    };
+    let env = base
+        .iter()
+        .filter(|(k, _)| !env.iter().any(|(added, _)| added == k))
+        .chain(env)
+        .map(|(k, v)| cstring(format!("{}={}", k, v).as_bytes()))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut env_ptrs = env.iter().map(|e| e.as_ptr()).collect::<Vec<_>>();
+    env_ptrs.push(ptr::null());
+
+    let mut pipe = [0; 2];
+    check(unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) })?;
+    let (stdin, writer) = (pipe[0], pipe[1]);
+    let pid = check(unsafe { libc::fork() })?;
+    if pid == 0 {
+        unsafe {
+            let _ = start(
+                stdin,
+                writer,
+                &argv_ptrs,
+                &env_ptrs,
+                setup.as_ref(),
+                &filter,
+            );
+            libc::_exit(127);
+        }
+    }
+    unsafe {
+        libc::close(stdin);
+    }
+    let mut writer = unsafe { File::from_raw_fd(writer) };
+    let input = input.to_owned();
+    // the program may not read its input before it is done
+    let feeder = thread::spawn(move || {
+        let _ = writer.write_all(input.as_bytes());
+    });
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let (done, watchdog) = match timeout {
+        Some(timeout) => {
+            let (done, wait) = mpsc::channel::<()>();
+            let timed_out = timed_out.clone();
+            let watchdog = thread::spawn(move || {
+                if let Err(mpsc::RecvTimeoutError::Timeout) = wait.recv_timeout(timeout) {
+                    timed_out.store(true, Ordering::SeqCst);
+                    // the program leads its own group, which outlives it while anything in it
+                    // is left, so the group cannot have been reused yet
+                    unsafe {
+                        libc::killpg(pid, libc::SIGKILL);
+                    }
+                }
+            });
+            (Some(done), Some(watchdog))
+        }
+        None => (None, None),
+    };
+    let res = follow(pid, &timed_out);
+    drop(done);
+    if let Some(watchdog) = watchdog {
+        let _ = watchdog.join();
+    }
+    let _ = feeder.join();
+    if timed_out.load(Ordering::SeqCst) {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "time limit exceeded",
+        ));
+    }
+    res
+}
+
+/// Runs in the child: stops until the tracer is ready, enters the sandbox if there is one, and
+/// execs the program.
+unsafe fn start(
+    stdin: libc::c_int,
+    writer: libc::c_int,
+    argv: &[*const libc::c_char],
+    env: &[*const libc::c_char],
+    setup: Option<&Setup>,
+    filter: &[libc::sock_filter],
+) -> io::Result<()> {
+    check(libc::dup2(stdin, 0))?;
+    check(libc::dup2(2, 1))?;
+    libc::close(writer);
+    // so that a timeout can kill everything it starts
+    check(libc::setpgid(0, 0))?;
+    check(libc::ptrace(
+        libc::PTRACE_TRACEME,
+        0,
+        ptr::null_mut::<libc::c_void>(),
+        ptr::null_mut::<libc::c_void>(),
+    ) as libc::c_int)?;
+    check(libc::raise(libc::SIGSTOP))?;
+    match setup {
+        // the setup ends with the filter
+        Some(setup) => setup.enter()?,
+        None => sandbox::install_filter(filter)?,
+    }
+    libc::execvpe(argv[0], argv.as_ptr(), env.as_ptr());
+    Err(io::Error::last_os_error())
+}
+
+/// Traces `pid` and its children until they have all exited. Once `timed_out` is set, kills
+/// them instead, including those that left the program's process group.
+fn follow(pid: libc::pid_t, timed_out: &AtomicBool) -> io::Result<(BTreeSet<u32>, ExitStatus)> {
+    let mut status = 0;
+    // the child stops itself once it is traced
+    check(unsafe { libc::waitpid(pid, &mut status, libc::__WALL) })?;
+    ptrace(libc::PTRACE_SETOPTIONS, pid, TRACE_OPTIONS as usize)?;
+    ptrace(libc::PTRACE_CONT, pid, 0)?;
+
+    let mut syscalls = BTreeSet::new();
+    let mut exit_status = None;
+    let mut tracees = HashSet::new();
+    tracees.insert(pid);
+    loop {
+        let tracee = unsafe { libc::waitpid(-1, &mut status, libc::__WALL) };
+        if tracee == -1 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::ECHILD) => break,
+                _ => return Err(e),
+            }
+        }
+        let stopped = libc::WIFSTOPPED(status);
+        if stopped {
+            tracees.insert(tracee);
+        } else {
+            tracees.remove(&tracee);
+            if tracee == pid {
+                exit_status = Some(ExitStatus::from_raw(status));
+            }
+        }
+        if timed_out.load(Ordering::SeqCst) {
+            for &tracee in &tracees {
+                unsafe {
+                    libc::kill(tracee, libc::SIGKILL);
+                }
+            }
+            continue;
+        }
+        if !stopped {
+            continue;
+        }
+        let signal = match status >> 16 {
+            0 => match libc::WSTOPSIG(status) {
+                // children start out stopped
+                libc::SIGSTOP => 0,
+                signal => signal,
+            },
+            libc::PTRACE_EVENT_SECCOMP => {
+                // fails if the tracee was killed in the meantime
+                if let Ok(syscall) = syscall_number(tracee) {
+                    syscalls.insert(syscall);
+                }
+                0
+            }
+            _ => 0,
+        };
+        let _ = ptrace(libc::PTRACE_CONT, tracee, signal as usize);
+    }
+    let exit_status =
+        exit_status.ok_or_else(|| io::Error::other("lost track of the traced program"))?;
+    Ok((syscalls, exit_status))
+}
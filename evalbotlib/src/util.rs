@@ -5,33 +5,31 @@ use std::path::Path;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-pub async fn encode<'a, T, P>(obj: &'a T, name: P) -> Result<(), String>
+use crate::EvalError;
+
+#[allow(clippy::needless_lifetimes)]
+pub async fn encode<'a, T, P>(obj: &'a T, name: P) -> Result<(), EvalError>
 where
     P: AsRef<Path> + Send + Display + 'static,
     T: Serialize,
 {
-    let toml_string = toml::to_string(obj).map_err(|e| format!("toml encode failed: {}", e))?;
-    let mut file = File::create(name)
-        .await
-        .map_err(|x| format!("could not open file: {}", x))?;
+    let toml_string = toml::to_string(obj)
+        .map_err(|e| EvalError::Config(format!("toml encode failed: {}", e)))?;
+    let mut file = File::create(name).await.map_err(EvalError::Io)?;
     file.write_all(&toml_string.into_bytes())
         .await
-        .map_err(|x| format!("could not write to file: {}", x))?;
+        .map_err(EvalError::Io)?;
     Ok(())
 }
 
-pub async fn decode<T, P>(name: P) -> Result<T, String>
+pub async fn decode<T, P>(name: P) -> Result<T, EvalError>
 where
     P: AsRef<Path> + Send + Display + 'static,
     T: DeserializeOwned,
 {
-    let mut file = File::open(name)
-        .await
-        .map_err(|x| format!("could not open file: {}", x))?;
+    let mut file = File::open(name).await.map_err(EvalError::Io)?;
     let mut buf = Vec::new();
-    file.read_to_end(&mut buf)
-        .await
-        .map_err(|x| format!("could not read file: {}", x))?;
+    file.read_to_end(&mut buf).await.map_err(EvalError::Io)?;
     toml::from_str(&String::from_utf8_lossy(&buf[..]))
-        .map_err(|x| format!("could not parse file: {:?}", x))
+        .map_err(|x| EvalError::Config(format!("could not parse file: {}", x)))
 }
@@ -0,0 +1,622 @@
+//! Checks a configuration for problems that parsing alone would not report, or would only report
+//! as an opaque error.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use toml::value::{Table, Value};
+
+use crate::cmdline::Cmdline;
+use crate::preset;
+use crate::sandbox::ROOT_UID;
+use crate::syscalls::SyscallList;
+use crate::template::{Template, WrapRule};
+use crate::{LanguageCfg, SandboxCfg};
+
+const SERVICE_KEYS: &[&str] = &[
+    "timeout",
+    "max_output_bytes",
+    "max_output_lines",
+    "max_concurrent",
+    "max_queue",
+    "presets",
+    "languages",
+];
+const LANGUAGE_KEYS: &[&str] = &[
+    "aliases",
+    "display_name",
+    "description",
+    "extension",
+    "highlight",
+    "code_before",
+    "code_after",
+    "timeout",
+    "max_output_bytes",
+    "max_output_lines",
+    "max_concurrent",
+    "max_queue",
+    "context_ttl",
+    "journal_size",
+    "templates",
+    "default_template",
+    "wrap_rules",
+    "vars",
+];
+const EXEC_KEYS: &[&str] = &["cmdline", "timeout_prefix", "memory_limit"];
+const SANDBOX_KEYS: &[&str] = &["cmdline", "timeout_prefix", "sandbox"];
+const NETWORK_KEYS: &[&str] = &["network_addr", "timeout_cmdline", "protocol", "context_ops"];
+const UNIX_SOCKET_KEYS: &[&str] = &[
+    "socket_addr",
+    "timeout_cmdline",
+    "protocol",
+    "context_ops",
+    "daemon",
+];
+const DAEMON_KEYS: &[&str] = &["cmdline", "restart_delay", "start_timeout"];
+const SANDBOX_CFG_KEYS: &[&str] = &[
+    "root",
+    "syscalls",
+    "hostname",
+    "uid",
+    "devices",
+    "network",
+    "tmp_size",
+    "cgroup",
+    "memory_limit",
+    "pids_limit",
+    "cpu_limit",
+];
+const WRAP_RULE_KEYS: &[&str] = &["matches", "template"];
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity {
+    /// Probably fine, but possibly not what was intended, or depends on the machine.
+    Warning,
+    /// The configuration will not load, or the language cannot work.
+    Error,
+}
+
+/// A problem found in a configuration.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    /// The language the problem is in, or `None` if it is not specific to one.
+    pub language: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.language {
+            Some(ref lang) => write!(f, "{}: language {}: {}", severity, lang, self.message),
+            None => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+struct Problems(Vec<ConfigProblem>);
+
+impl Problems {
+    fn push(&mut self, severity: Severity, language: Option<&str>, message: String) {
+        self.0.push(ConfigProblem {
+            severity,
+            language: language.map(str::to_owned),
+            message,
+        });
+    }
+
+    fn error(&mut self, language: Option<&str>, message: String) {
+        self.push(Severity::Error, language, message);
+    }
+
+    fn warning(&mut self, language: Option<&str>, message: String) {
+        self.push(Severity::Warning, language, message);
+    }
+}
+
+pub fn validate(toml: &str) -> Vec<ConfigProblem> {
+    let mut problems = Problems(Vec::new());
+    let root = match toml.parse::<Value>() {
+        Ok(Value::Table(root)) => root,
+        Ok(_) => unreachable!("a TOML document is always a table"),
+        Err(e) => {
+            problems.error(None, format!("not valid TOML: {}", e));
+            return problems.0;
+        }
+    };
+
+    check_keys(&mut problems, None, &root, &[SERVICE_KEYS]);
+    if !matches!(root.get("timeout"), Some(Value::Integer(_))) {
+        problems.error(None, "missing or invalid timeout".to_owned());
+    }
+
+    let presets = match root.get("presets") {
+        Some(Value::Table(presets)) => presets.clone(),
+        Some(_) => {
+            problems.error(None, "presets is not a table".to_owned());
+            Table::new()
+        }
+        None => Table::new(),
+    };
+
+    match root.get("languages") {
+        Some(Value::Table(languages)) => {
+            let mut names = languages.keys().collect::<Vec<_>>();
+            names.sort();
+            let mut aliases = HashMap::new();
+            for name in names {
+                match languages[name] {
+                    Value::Table(ref lang) => match preset::resolve(lang, &presets) {
+                        Ok(lang) => {
+                            validate_language(&mut problems, name, &lang);
+                            check_aliases(&mut problems, name, &lang, languages, &mut aliases);
+                        }
+                        Err(e) => problems.error(Some(name), e),
+                    },
+                    _ => problems.error(Some(name), "not a table".to_owned()),
+                }
+            }
+        }
+        Some(_) => problems.error(None, "languages is not a table".to_owned()),
+        None => problems.warning(None, "no languages configured".to_owned()),
+    }
+
+    problems.0
+}
+
+fn check_aliases<'a>(
+    problems: &mut Problems,
+    name: &'a str,
+    lang: &Table,
+    languages: &Table,
+    aliases: &mut HashMap<String, &'a str>,
+) {
+    let list = match lang.get("aliases") {
+        Some(Value::Array(list)) => list,
+        _ => return,
+    };
+    for alias in list.iter().filter_map(Value::as_str) {
+        if languages.contains_key(alias) {
+            problems.error(
+                Some(name),
+                format!("alias {} is the name of a language", alias),
+            );
+        } else if let Some(other) = aliases.insert(alias.to_owned(), name) {
+            problems.error(
+                Some(name),
+                format!("alias {} is also an alias of {}", alias, other),
+            );
+        }
+    }
+}
+
+fn validate_language(problems: &mut Problems, name: &str, lang: &Table) {
+    let lang_name = Some(name);
+    let backend_keys = match (
+        lang.contains_key("cmdline"),
+        lang.contains_key("network_addr"),
+        lang.contains_key("socket_addr"),
+    ) {
+        (true, false, false) if lang.contains_key("sandbox") => SANDBOX_KEYS,
+        (true, false, false) => EXEC_KEYS,
+        (false, true, false) => NETWORK_KEYS,
+        (false, false, true) => UNIX_SOCKET_KEYS,
+        (false, false, false) => {
+            check_keys(
+                problems,
+                lang_name,
+                lang,
+                &[
+                    LANGUAGE_KEYS,
+                    EXEC_KEYS,
+                    SANDBOX_KEYS,
+                    NETWORK_KEYS,
+                    UNIX_SOCKET_KEYS,
+                ],
+            );
+            problems.error(
+                lang_name,
+                "no backend; one of cmdline, network_addr or socket_addr is required".to_owned(),
+            );
+            return;
+        }
+        _ => {
+            problems.error(
+                lang_name,
+                "only one of cmdline, network_addr or socket_addr may be given".to_owned(),
+            );
+            return;
+        }
+    };
+    check_keys(problems, lang_name, lang, &[LANGUAGE_KEYS, backend_keys]);
+    if let Some(Value::Table(daemon)) = lang.get("daemon") {
+        check_keys(problems, lang_name, daemon, &[DAEMON_KEYS]);
+    }
+    if let Some(Value::Table(sandbox)) = lang.get("sandbox") {
+        check_keys(problems, lang_name, sandbox, &[SANDBOX_CFG_KEYS]);
+    }
+    if let Some(Value::Array(rules)) = lang.get("wrap_rules") {
+        for rule in rules {
+            if let Value::Table(rule) = rule {
+                check_keys(problems, lang_name, rule, &[WRAP_RULE_KEYS]);
+            }
+        }
+    }
+
+    let cfg = match Value::Table(lang.clone()).try_into::<LanguageCfg>() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            problems.error(lang_name, format!("invalid: {}", e));
+            return;
+        }
+    };
+
+    let mut templates = cfg.templates.iter().collect::<Vec<_>>();
+    templates.sort();
+    for (template_name, template) in templates {
+        if let Err(e) = Template::parse(template, &cfg.vars) {
+            problems.error(lang_name, format!("template {}: {}", template_name, e));
+        }
+    }
+    if let Some(ref default) = cfg.default_template {
+        if !cfg.templates.contains_key(default) {
+            problems.error(
+                lang_name,
+                format!("default_template {} is not defined", default),
+            );
+        }
+        if cfg.code_before.is_some() || cfg.code_after.is_some() {
+            problems.warning(
+                lang_name,
+                "code_before and code_after are not used when default_template is set".to_owned(),
+            );
+        }
+    }
+    for (i, rule) in cfg.wrap_rules.iter().enumerate() {
+        if let Err(e) = WrapRule::new(&rule.matches, None) {
+            problems.error(lang_name, format!("wrap rule {}: {}", i, e));
+        }
+        match rule.template {
+            Some(ref template) if !cfg.templates.contains_key(template) => problems.error(
+                lang_name,
+                format!("wrap rule {}: template {} is not defined", i, template),
+            ),
+            _ => {}
+        }
+    }
+
+    let protocol = match cfg.backend {
+        crate::BackendCfg::Exec(_) | crate::BackendCfg::Sandbox(_) => None,
+        crate::BackendCfg::Network(ref net) => Some(net.protocol.unwrap_or(1)),
+        crate::BackendCfg::UnixSocket(ref unix) => Some(unix.protocol.unwrap_or(1)),
+    };
+    if cfg.backend.context_ops() && protocol.unwrap_or(0) < 2 {
+        problems.error(lang_name, "context_ops needs protocol v2".to_owned());
+    }
+    if cfg.context_ttl.is_some() && !cfg.backend.context_ops() {
+        problems.warning(
+            lang_name,
+            "context_ttl has no effect without context_ops".to_owned(),
+        );
+    }
+    if cfg.journal_size.is_some() && protocol.is_none() {
+        problems.warning(
+            lang_name,
+            "journal_size has no effect without a persistent evaluator".to_owned(),
+        );
+    }
+
+    match cfg.backend {
+        crate::BackendCfg::Exec(ref exec) => {
+            let cmdline = check_cmdline(
+                problems,
+                name,
+                &exec.cmdline,
+                exec.timeout_prefix.as_deref(),
+                exec.memory_limit,
+                &cfg,
+            );
+            if let Some(path) = cmdline.as_ref().and_then(Cmdline::program) {
+                check_executable(problems, lang_name, "cmdline", path);
+            }
+        }
+        crate::BackendCfg::Sandbox(ref sandboxed) => {
+            let cmdline = check_cmdline(
+                problems,
+                name,
+                &sandboxed.cmdline,
+                sandboxed.timeout_prefix.as_deref(),
+                sandboxed.sandbox.memory_limit,
+                &cfg,
+            );
+            if cmdline.as_ref().is_some_and(Cmdline::has_workdir) {
+                problems.error(
+                    lang_name,
+                    "cmdline: {WORKDIR} is not mounted in the sandbox; use /tmp".to_owned(),
+                );
+            }
+            check_sandbox(
+                problems,
+                lang_name,
+                &sandboxed.sandbox,
+                cmdline.as_ref().and_then(Cmdline::program),
+            );
+        }
+        crate::BackendCfg::Network(ref net) => {
+            if !net.network_addr.contains(':') {
+                problems.error(
+                    lang_name,
+                    format!("network_addr {} has no port", net.network_addr),
+                );
+            }
+            check_persistent(problems, lang_name, &net.timeout_cmdline, net.protocol);
+        }
+        crate::BackendCfg::UnixSocket(ref unix) => match unix.daemon {
+            Some(ref daemon) => {
+                // the socket is created when the daemon is started
+                let dir = Path::new(&unix.socket_addr)
+                    .parent()
+                    .filter(|d| !d.as_os_str().is_empty())
+                    .unwrap_or_else(|| Path::new("."));
+                match fs::metadata(dir) {
+                    Ok(m) if m.is_dir() => {}
+                    Ok(_) => problems.error(
+                        lang_name,
+                        format!("socket_addr: {} is not a directory", dir.display()),
+                    ),
+                    Err(e) => problems
+                        .warning(lang_name, format!("socket_addr: {}: {}", dir.display(), e)),
+                }
+                match daemon.cmdline.first() {
+                    None => problems.error(lang_name, "empty daemon cmdline".to_owned()),
+                    Some(path) => check_executable(problems, lang_name, "daemon cmdline", path),
+                }
+                if unix.timeout_cmdline.is_some() {
+                    problems.warning(
+                        lang_name,
+                        "timeout_cmdline is not used; the daemon is restarted instead".to_owned(),
+                    );
+                }
+                if let Err(e) = crate::protocol::check_version(unix.protocol.unwrap_or(1)) {
+                    problems.error(lang_name, e.to_string());
+                }
+            }
+            None => {
+                match fs::metadata(&unix.socket_addr) {
+                    Ok(m) if m.file_type().is_socket() => {}
+                    Ok(_) => problems.error(
+                        lang_name,
+                        format!("socket_addr {} is not a socket", unix.socket_addr),
+                    ),
+                    Err(e) => problems.warning(
+                        lang_name,
+                        format!("socket_addr {}: {}", unix.socket_addr, e),
+                    ),
+                }
+                check_persistent(problems, lang_name, &unix.timeout_cmdline, unix.protocol);
+            }
+        },
+    }
+}
+
+fn check_cmdline(
+    problems: &mut Problems,
+    name: &str,
+    cmdline: &[String],
+    timeout_prefix: Option<&str>,
+    memory_limit: Option<u64>,
+    cfg: &LanguageCfg,
+) -> Option<Cmdline> {
+    let lang_name = Some(name);
+    if timeout_prefix.is_some() && !cmdline.iter().any(|a| a.contains("{TIMEOUT}")) {
+        problems.warning(
+            lang_name,
+            "timeout_prefix is set, but cmdline has no {TIMEOUT}".to_owned(),
+        );
+    }
+    match Cmdline::parse(cmdline, name, timeout_prefix, memory_limit, &cfg.vars) {
+        Ok(cmdline) => Some(cmdline),
+        Err(e) => {
+            problems.error(lang_name, format!("cmdline: {}", e));
+            None
+        }
+    }
+}
+
+fn check_sandbox(
+    problems: &mut Problems,
+    lang_name: Option<&str>,
+    sandbox: &SandboxCfg,
+    program: Option<&str>,
+) {
+    let root = Path::new(&sandbox.root);
+    match fs::metadata(root) {
+        Ok(m) if m.is_dir() => {
+            if let Some(path) = program.filter(|p| p.starts_with('/')) {
+                let inside = root.join(&path[1..]);
+                if !inside.exists() {
+                    problems.warning(
+                        lang_name,
+                        format!("cmdline: {} not found in {}", path, sandbox.root),
+                    );
+                }
+            }
+        }
+        Ok(_) => problems.error(
+            lang_name,
+            format!("sandbox: root {} is not a directory", sandbox.root),
+        ),
+        Err(e) => problems.warning(lang_name, format!("sandbox: root {}: {}", sandbox.root, e)),
+    }
+    if sandbox.uid == Some(0) {
+        problems.error(lang_name, format!("sandbox: {}", ROOT_UID));
+    }
+    match sandbox.syscalls {
+        Some(ref path) => {
+            if let Err(e) = SyscallList::read(path) {
+                problems.error(lang_name, format!("sandbox: syscalls: {}", e));
+            }
+        }
+        None => problems.warning(
+            lang_name,
+            "sandbox: no syscalls list, so any syscall is allowed".to_owned(),
+        ),
+    }
+    match sandbox.cgroup {
+        Some(ref cgroup) => {
+            if !Path::new(cgroup).join("cgroup.procs").exists() {
+                problems.warning(
+                    lang_name,
+                    format!("sandbox: cgroup {} is not a cgroup v2 directory", cgroup),
+                );
+            }
+        }
+        None => {
+            if sandbox.memory_limit.is_some()
+                || sandbox.pids_limit.is_some()
+                || sandbox.cpu_limit.is_some()
+            {
+                problems.warning(
+                    lang_name,
+                    "sandbox: memory_limit, pids_limit and cpu_limit need a cgroup".to_owned(),
+                );
+            }
+        }
+    }
+}
+
+fn check_persistent(
+    problems: &mut Problems,
+    lang_name: Option<&str>,
+    timeout_cmdline: &Option<Vec<String>>,
+    protocol: Option<u32>,
+) {
+    match timeout_cmdline.as_ref().map(|c| c.first()) {
+        Some(None) => problems.error(lang_name, "empty timeout_cmdline".to_owned()),
+        Some(Some(path)) => check_executable(problems, lang_name, "timeout_cmdline", path),
+        None => problems.warning(
+            lang_name,
+            "no timeout_cmdline; a daemon that times out will not be killed".to_owned(),
+        ),
+    }
+    if let Err(e) = crate::protocol::check_version(protocol.unwrap_or(1)) {
+        problems.error(lang_name, e.to_string());
+    }
+}
+
+fn check_keys(problems: &mut Problems, lang_name: Option<&str>, table: &Table, known: &[&[&str]]) {
+    let mut keys = table.keys().collect::<Vec<_>>();
+    keys.sort();
+    for key in keys {
+        if !known.iter().any(|k| k.contains(&key.as_str())) {
+            let message = match suggest(key, known) {
+                Some(s) => format!("unknown key {} (did you mean {}?)", key, s),
+                None => format!("unknown key {}", key),
+            };
+            problems.error(lang_name, message);
+        }
+    }
+}
+
+/// Finds a known key that is a likely typo of `key`.
+fn suggest<'a>(key: &str, known: &[&[&'a str]]) -> Option<&'a str> {
+    known
+        .iter()
+        .flat_map(|k| k.iter())
+        .map(|k| (edit_distance(key, k), *k))
+        .filter(|&(d, _)| d <= 2)
+        .min()
+        .map(|(_, k)| k)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + if ca == *cb { 0 } else { 1 };
+            cur.push(sub.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+fn check_executable(problems: &mut Problems, lang_name: Option<&str>, key: &str, path: &str) {
+    match find_executable(path) {
+        Some(found) => match fs::metadata(&found) {
+            Ok(m) if m.is_file() && m.permissions().mode() & 0o111 != 0 => {}
+            _ => problems.warning(
+                lang_name,
+                format!("{}: {} is not an executable file", key, found.display()),
+            ),
+        },
+        None => problems.warning(lang_name, format!("{}: {} not found", key, path)),
+    }
+}
+
+fn find_executable(path: &str) -> Option<PathBuf> {
+    if path.contains('/') {
+        Some(PathBuf::from(path)).filter(|p| p.exists())
+    } else {
+        env::var_os("PATH").and_then(|paths| {
+            env::split_paths(&paths)
+                .map(|dir| dir.join(path))
+                .find(|p| Path::new(p).exists())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Severity;
+
+    #[test]
+    fn test_validate() {
+        let problems = super::validate(
+            r#"
+timeout = 20
+
+[languages.py]
+socket_adr = "/run/eval/pyeval.sock"
+
+[languages.sh]
+cmdline = ["sh"]
+timeout_prefix = "--timeout="
+"#,
+        );
+        let messages = problems
+            .iter()
+            .map(|p| (p.severity, p.language.as_deref(), p.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    Severity::Error,
+                    Some("py"),
+                    "unknown key socket_adr (did you mean socket_addr?)"
+                ),
+                (
+                    Severity::Error,
+                    Some("py"),
+                    "no backend; one of cmdline, network_addr or socket_addr is required"
+                ),
+                (
+                    Severity::Warning,
+                    Some("sh"),
+                    "timeout_prefix is set, but cmdline has no {TIMEOUT}"
+                ),
+            ]
+        );
+    }
+}
@@ -14,11 +14,43 @@ class Request:
         self.result = None
         self.output = None
 
+MAGIC = b'EVAL'
+
+REQ_END, REQ_TIMEOUT, REQ_CONTEXT, REQ_CODE, REQ_OP, REQ_STDIN = range(6)
+RESP_END, RESP_STDOUT, RESP_STDERR, RESP_VALUE, RESP_STATUS, RESP_ERROR, RESP_CONTEXT = range(7)
+OP_EVAL, OP_RESET, OP_DROP, OP_LIST = range(4)
+
 def readinput(inbuf):
-    timeout, keysize, codesize = struct.unpack('III', inbuf.read(12))
+    head = inbuf.read(4)
+    if not head:
+        return None # connection closed without a request
+    if head == MAGIC:
+        return readinput_v2(inbuf)
+    timeout, = struct.unpack('I', head)
+    keysize, codesize = struct.unpack('II', inbuf.read(8))
     key = inbuf.read(keysize).decode('utf-8')
     code = inbuf.read(codesize).decode('utf-8')
-    return (timeout, key, code)
+    return (1, timeout, key, code, OP_EVAL, '')
+
+def readinput_v2(inbuf):
+    version, = struct.unpack('I', inbuf.read(4))
+    timeout, key, code, op, stdin = 0, '', '', OP_EVAL, ''
+    while True:
+        kind, size = struct.unpack('II', inbuf.read(8))
+        data = inbuf.read(size)
+        if kind == REQ_END:
+            break
+        elif kind == REQ_TIMEOUT:
+            timeout, = struct.unpack('I', data)
+        elif kind == REQ_CONTEXT:
+            key = data.decode('utf-8')
+        elif kind == REQ_CODE:
+            code = data.decode('utf-8')
+        elif kind == REQ_OP:
+            op, = struct.unpack('I', data)
+        elif kind == REQ_STDIN:
+            stdin = data.decode('utf-8')
+    return (2, timeout, key, code, op, stdin)
 
 def writeoutput(outbuf, opt):
     try:
@@ -31,6 +63,21 @@ def writeoutput(outbuf, opt):
         print("error returning output:")
         traceback.print_exc(file=sys.stderr)
 
+def writeframes(outbuf, frames):
+    try:
+        outbuf.write(MAGIC)
+        outbuf.write(struct.pack('I', 2))
+        for kind, data in frames + [(RESP_END, '')]:
+            if isinstance(data, str):
+                data = data.encode('utf-8')
+            outbuf.write(struct.pack('II', kind, len(data)))
+            if data:
+                outbuf.write(data)
+        outbuf.flush()
+    except:
+        print("error returning output:")
+        traceback.print_exc(file=sys.stderr)
+
 class PyEvalServer(socketserver.UnixStreamServer):
     def server_bind(self):
         os.set_inheritable(3, False)
@@ -51,29 +98,61 @@ class RequestHandler(socketserver.StreamRequestHandler):
     def handle_int(self):
         global codebufs, etors
 
-        timeout, key, codefragment = readinput(self.rfile)
+        request = readinput(self.rfile)
+        if request is None:
+            return
+        version, timeout, key, codefragment, op, stdin = request
+        if op != OP_EVAL:
+            writeframes(self.wfile, handle_op(op, key))
+            return
+
         codebuf = codebufs.setdefault(key, [])
         etor = etors.setdefault(key, PyEval())
 
         codebuf.append(codefragment)
         source = '\n'.join(codebuf)
 
+        out = io.StringIO()
+        err = out if version == 1 else io.StringIO()
+        more = False
         try:
-            out = io.StringIO()
+            sys.stdin = io.StringIO(stdin)
             with contextlib.redirect_stdout(out):
-                with contextlib.redirect_stderr(out):
+                with contextlib.redirect_stderr(err):
                     more = etor.runsource(source)
         except:
-            traceback.print_exc(file=out)
+            traceback.print_exc(file=err)
+        finally:
+            sys.stdin = sys.__stdin__
 
-        if not more:
-            codebuf.clear()
-            writeoutput(self.wfile, out.getvalue())
-        elif more:
-            writeoutput(self.wfile, "(continue...)")
+        if more:
+            output = [(RESP_STDOUT, "(continue...)")]
         else:
             codebuf.clear()
-            writeoutput(self.wfile, "something weird happened")
+            output = [(RESP_STDOUT, out.getvalue())]
+            if err is not out:
+                output.append((RESP_STDERR, err.getvalue()))
+
+        if version == 1:
+            writeoutput(self.wfile, ''.join(data for _, data in output))
+        else:
+            writeframes(self.wfile, output)
+
+def handle_op(op, key):
+    if op == OP_RESET or op == OP_DROP:
+        # a context is created again on its next use, so resetting and dropping are the same
+        etors.pop(key, None)
+        codebufs.pop(key, None)
+        return []
+    elif op == OP_LIST:
+        return [(RESP_CONTEXT, struct.pack('q', context_size(etor)) + key.encode('utf-8'))
+                for key, etor in etors.items()]
+    else:
+        return [(RESP_ERROR, 'unknown operation %d' % op)]
+
+def context_size(etor):
+    # only a rough estimate; objects referenced from the variables are not counted
+    return sum(sys.getsizeof(v) for v in etor.locals.values())
 
 etors = {}
 codebufs = {}
@@ -1,7 +1,9 @@
-use evalbotlib::{util, EvalService, Language};
+mod ratelimit;
+
+use evalbotlib::{util, EvalError, EvalRequest, EvalResult, EvalService, Language, TimeLimit};
 
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -9,16 +11,27 @@ use futures::StreamExt;
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use telegram_bot::*;
-use tokio::sync::RwLock;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Mutex, RwLock};
+
+use ratelimit::{BucketCfg, RateLimiter};
 
 static WHITELIST_FILENAME: &'static str = "tgwhitelist.toml";
+const GROUP_MAX_LINES: usize = 10;
+/// Telegram rejects messages longer than 4096 characters; this leaves room for what follows the
+/// output.
+const MAX_OUTPUT_CHARS: usize = 3500;
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 struct TgCfg {
     owners: HashSet<i64>,
     msg_owner_id: Option<i64>,
     bot_id: String,
-    lang_subst: HashMap<String, String>,
+    config_watch_interval: Option<u64>,
+    rate_limit_user: Option<BucketCfg>,
+    rate_limit_chat: Option<BucketCfg>,
+    show_usage: Option<bool>,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
@@ -64,22 +77,42 @@ impl TgWhitelist {
 struct TgSvc {
     config: TgCfg,
     whitelist: RwLock<TgWhitelist>,
+    rate_limiter: Mutex<RateLimiter>,
     service: EvalService,
     api: Api,
     bot_user: User,
     username: String,
 }
 
-fn telegram_wrap_result(s: &str, group: bool) -> String {
-    // FIXME configurable max-lines and max-bytes
+fn telegram_wrap_result(result: &EvalResult, group: bool, show_usage: bool) -> String {
+    let mut r = telegram_wrap_output(result, group);
+    if let (true, Some(usage)) = (show_usage, result.usage) {
+        r.push('\n');
+        r.push_str(&usage.to_string());
+    }
+    r
+}
+
+fn telegram_wrap_output(result: &EvalResult, group: bool) -> String {
+    // groups are only shown the first lines, status line included; the program's output is only
+    // limited by the language
+    let s = result.to_string();
     if s.is_empty() {
         "no output".to_owned()
     } else {
         let mut r = "<pre>".to_owned();
-        let input = s.as_bytes();
-        let mut cut_input = String::from_utf8_lossy(&input[..512.min(input.len())]);
+        let mut cut_input = Cow::Borrowed(s.as_str());
         if group {
-            cut_input = Cow::Owned(cut_input.lines().take(10).collect::<Vec<_>>().join("\n"));
+            cut_input = Cow::Owned(
+                s.lines()
+                    .take(GROUP_MAX_LINES)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            );
+        }
+        // the library's output limits are configurable, and may be too large for a message
+        if let Some((end, _)) = cut_input.char_indices().nth(MAX_OUTPUT_CHARS) {
+            cut_input = Cow::Owned(cut_input[..end].to_owned());
         }
         r.push_str(
             &cut_input
@@ -93,8 +126,9 @@ fn telegram_wrap_result(s: &str, group: bool) -> String {
                 .replace('"', "&quot;"),
         );
         r.push_str("</pre>");
-        if cut_input.len() + 1 // we also cut off the trailing \n
-            < input.len()
+        if result.truncated
+            || cut_input.len() + 1 // we also cut off the trailing \n
+                < s.len()
         {
             r.push_str("... (truncated)");
         }
@@ -102,6 +136,29 @@ fn telegram_wrap_result(s: &str, group: bool) -> String {
     }
 }
 
+fn telegram_error_message(msg_id: MessageId, e: &EvalError) -> String {
+    match e {
+        EvalError::Timeout => "Time limit exceeded.".to_owned(),
+        EvalError::Busy => "Too many evaluations are running. Try again later.".to_owned(),
+        EvalError::UnknownTemplate(_) => "No such template for this language.".to_owned(),
+        EvalError::Replay(_) => {
+            warn!("({}) {}", msg_id, e);
+            "The evaluator lost this chat's state and could not restore it. Try again to start over."
+                .to_owned()
+        }
+        // such as input for a language that reads the code from stdin
+        EvalError::Unsupported(what) => format!("Not supported: {}.", what),
+        EvalError::Spawn(_) | EvalError::Connect(_) => {
+            error!("({}) evaluator unavailable: {}", msg_id, e);
+            "The evaluator for this language is unavailable. Try again later.".to_owned()
+        }
+        _ => {
+            error!("({}) evaluation failed: {}", msg_id, e);
+            "Something went wrong while evaluating.".to_owned()
+        }
+    }
+}
+
 fn is_from_owner(msg: &Message, tgsvc: &TgSvc) -> bool {
     tgsvc.is_owner(msg.from.to_user_id())
 }
@@ -143,6 +200,31 @@ async fn verify_allowed(chat: &MessageChat, tgsvc: &Arc<TgSvc>) -> Result<(), ()
     }
 }
 
+async fn verify_rate_limit(msg: &Message, tgsvc: &Arc<TgSvc>) -> Result<(), ()> {
+    if is_from_owner(msg, tgsvc) {
+        return Ok(());
+    }
+
+    let user_id = msg.from.id.into();
+    let chat_id = msg.chat.id().into();
+    let checked = tgsvc.rate_limiter.lock().await.check(user_id, chat_id);
+    if let Err(wait) = checked {
+        info!(
+            "({}) rate limited user {} in chat {} for {:?}",
+            msg.id, user_id, chat_id, wait
+        );
+        let mut request = SendMessage::new(
+            &msg.chat,
+            format!("Slow down! Try again in {} s.", wait.as_secs() + 1),
+        );
+        request.reply_to(msg);
+        tokio::spawn(tgsvc.api.send(request));
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
 async fn handle_update(update: Update, tgsvc: Arc<TgSvc>) -> Result<(), ()> {
     let message = match update {
         Update {
@@ -227,14 +309,21 @@ async fn handle_message(message: Message, tgsvc: &Arc<TgSvc>) -> Result<(), ()>
         "/block" => handle_whitelist_mod(tgsvc, &message, args, WhitelistModOp::Block).await?,
         "/unblock" => handle_whitelist_mod(tgsvc, &message, args, WhitelistModOp::Unblock).await?,
         "/leave" => handle_leave(tgsvc, &message, args).await?,
+        "/reload" => handle_reload(tgsvc, &message).await?,
+        "/reset" => handle_reset(tgsvc, &message, args).await?,
+        "/langs" => handle_langs(tgsvc, &message).await?,
         _ => {
             let (cmd, is_hash) = if cmd.ends_with('#') {
                 (&cmd[1..cmd.len() - 1], true)
             } else {
                 (&cmd[1..], false)
             };
-            if let Some((_, lang)) = tgsvc.service.langs().find(|(name, _)| *name == cmd) {
-                handle_eval(tgsvc, &message, args, lang, is_hash).await?;
+            // `/rs:program` picks one of the language's templates
+            let mut split = cmd.splitn(2, ':');
+            let cmd = split.next().expect("First split should exist");
+            let template = split.next();
+            if let Some(lang) = tgsvc.service.get(cmd) {
+                handle_eval(tgsvc, &message, args, &lang, template, is_hash).await?;
             }
         }
     }
@@ -247,9 +336,11 @@ async fn handle_eval(
     msg: &Message,
     args: &str,
     lang: &Arc<Language>,
+    template: Option<&str>,
     is_hash: bool,
 ) -> Result<(), ()> {
     verify_allowed(&msg.chat, tgsvc).await?;
+    verify_rate_limit(msg, tgsvc).await?;
     let no_limit = is_hash && is_from_owner(&msg, tgsvc);
     let is_group = if let MessageChat::Private(_) = msg.chat {
         false
@@ -259,30 +350,42 @@ async fn handle_eval(
     let chat_id = msg.chat.id();
     let msg_id = msg.id;
     info!("({}) evaluating from {:?}: {:?}", msg_id, msg.from, args);
-    let code = {
-        let mut r = args.to_owned();
-        r.push_str("\n");
-        r
-    };
-
-    let eval_result = lang
-        .eval(
-            code,
-            if no_limit { Some(0) } else { None },
-            Some(format!("tg{}", chat_id)),
-        )
-        .await;
+    let (code, stdin, prog_args) = split_input(args);
+
+    let mut request = EvalRequest::new(code)
+        .context(chat_context(chat_id))
+        .args(prog_args)
+        .requester(format!("tg{}", msg.from.id));
+    if let Some(template) = template {
+        request = request.template(template);
+    }
+    if let Some(stdin) = stdin {
+        request = request.stdin(stdin);
+    }
+    if no_limit {
+        request = request.time_limit(TimeLimit::Unlimited);
+    }
+    if is_from_owner(&msg, tgsvc) {
+        request = request.priority(1);
+    }
+    let mut eval_result = lang.eval(request.clone()).await;
+    if let Err(EvalError::Connect(ref e)) = eval_result {
+        // the daemon may be restarting after a timeout kill; give it a moment
+        warn!(
+            "({}) error connecting to evaluator: {}; retrying",
+            msg_id, e
+        );
+        tokio::time::sleep(CONNECT_RETRY_DELAY).await;
+        eval_result = lang.eval(request).await;
+    }
     let ok = eval_result.is_ok();
-    info!(
-        "({}) result: {:?}",
-        msg_id,
-        eval_result.as_ref().map(|x| x.as_str()).unwrap_or("")
-    );
+    info!("({}) result: {:?}", msg_id, eval_result);
     let mut request = SendMessage::new(
         &msg.chat,
-        eval_result
-            .map(|r| telegram_wrap_result(&r, is_group))
-            .unwrap_or_else(|e| e),
+        match eval_result {
+            Ok(r) => telegram_wrap_result(&r, is_group, tgsvc.config.show_usage.unwrap_or(false)),
+            Err(e) => telegram_error_message(msg_id, &e),
+        },
     );
     request.reply_to(msg);
     if ok {
@@ -293,6 +396,96 @@ async fn handle_eval(
     Ok(())
 }
 
+/// Splits a message into the code and the program's input and arguments, which can follow the
+/// code in sections starting with a `--- stdin` or `--- args` line.
+fn split_input(text: &str) -> (String, Option<String>, Vec<String>) {
+    let mut code = Vec::new();
+    let mut stdin: Option<Vec<&str>> = None;
+    let mut args = Vec::new();
+    let mut section = &mut code;
+    let mut in_args = false;
+    for line in text.split('\n') {
+        match line.trim() {
+            "--- stdin" => {
+                section = stdin.get_or_insert_with(Vec::new);
+                in_args = false;
+            }
+            "--- args" => in_args = true,
+            _ if in_args => args.extend(line.split_whitespace().map(|a| a.to_owned())),
+            _ => section.push(line),
+        }
+    }
+    (code.join("\n"), stdin.map(|s| s.join("\n")), args)
+}
+
+/// Resets the chat's context in the given language, or in all languages that have contexts.
+async fn handle_reset(tgsvc: &Arc<TgSvc>, msg: &Message, args: &str) -> Result<(), ()> {
+    verify_allowed(&msg.chat, tgsvc).await?;
+    let context = chat_context(msg.chat.id());
+    let resp = match args.split_whitespace().next() {
+        Some(name) => match tgsvc.service.get(name) {
+            Some(lang) => match lang.reset_context(&context).await {
+                Ok(()) => format!("Reset {}.", name),
+                Err(EvalError::Unsupported(_)) => format!("{} does not keep any state.", name),
+                Err(e) => telegram_error_message(msg.id, &e),
+            },
+            None => format!("Unknown language {}.", name),
+        },
+        None => {
+            let mut reset = Vec::new();
+            for (name, lang) in tgsvc.service.langs() {
+                match lang.reset_context(&context).await {
+                    Ok(()) => reset.push(name),
+                    Err(EvalError::Unsupported(_)) => {}
+                    Err(e) => warn!("({}) failed to reset {}: {}", msg.id, name, e),
+                }
+            }
+            if reset.is_empty() {
+                "Nothing to reset.".to_owned()
+            } else {
+                format!("Reset {}.", reset.join(", "))
+            }
+        }
+    };
+    let mut request = SendMessage::new(&msg.chat, resp);
+    request.reply_to(msg);
+    tokio::spawn(tgsvc.api.send(request));
+    Ok(())
+}
+
+/// Lists the languages, with their aliases and descriptions.
+async fn handle_langs(tgsvc: &Arc<TgSvc>, msg: &Message) -> Result<(), ()> {
+    verify_allowed(&msg.chat, tgsvc).await?;
+    let mut resp = String::new();
+    for (name, lang) in tgsvc.service.langs() {
+        resp.push('/');
+        resp.push_str(&name);
+        for alias in lang.aliases() {
+            resp.push_str(", /");
+            resp.push_str(alias);
+        }
+        resp.push_str(" - ");
+        resp.push_str(lang.display_name());
+        if let Some(description) = lang.description() {
+            resp.push_str(": ");
+            resp.push_str(description);
+        }
+        resp.push('\n');
+    }
+    if resp.is_empty() {
+        resp.push_str("No languages.");
+    }
+    let mut request = SendMessage::new(&msg.chat, resp);
+    request.reply_to(msg);
+    tokio::spawn(tgsvc.api.send(request));
+    Ok(())
+}
+
+/// The evaluation context shared by everyone in a chat.
+fn chat_context(chat_id: ChatId) -> String {
+    format!("tg{}", chat_id)
+}
+
 #[derive(Clone, Copy)]
 enum WhitelistToggleOp {
     TogglePrivate,
@@ -410,6 +603,35 @@ async fn handle_leave(tgsvc: &Arc<TgSvc>, msg: &Message, args: &str) -> Result<(
     Ok(())
 }
 
+async fn handle_reload(tgsvc: &Arc<TgSvc>, msg: &Message) -> Result<(), ()> {
+    if !is_from_owner(&msg, tgsvc) {
+        return Ok(());
+    }
+
+    let resp = match tgsvc.service.reload().await {
+        Ok(()) => format!("Reloaded, {} languages", tgsvc.service.langs().len()),
+        Err(e) => format!("Reload failed, keeping old configuration: {}", e),
+    };
+    tokio::spawn(tgsvc.api.send(SendMessage::new(&msg.chat, resp)));
+    Ok(())
+}
+
+async fn reload_on_sighup(tgsvc: Arc<TgSvc>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match tgsvc.service.reload().await {
+            Ok(()) => info!("SIGHUP received, reloaded evalbot.toml"),
+            Err(e) => error!("SIGHUP received, but failed to reload evalbot.toml: {}", e),
+        }
+    }
+}
+
 impl TgSvc {
     async fn run() -> Result<(), ()> {
         let cfg = util::decode::<TgCfg, _>("evalbot.tg.toml")
@@ -419,6 +641,16 @@ impl TgSvc {
                 cfg
             })
             .map_err(|e| error!("failed to read evalbot.tg.toml: {}", e))?;
+        for (name, bucket) in [
+            ("rate_limit_user", &cfg.rate_limit_user),
+            ("rate_limit_chat", &cfg.rate_limit_chat),
+        ] {
+            if let Some(bucket) = bucket {
+                bucket
+                    .validate()
+                    .map_err(|e| error!("invalid evalbot.tg.toml: {}: {}", name, e))?;
+            }
+        }
         let wl = util::decode::<TgWhitelist, _>(WHITELIST_FILENAME)
             .await
             .or_else(|e| {
@@ -441,6 +673,7 @@ impl TgSvc {
             .map_err(|e| error!("failed to read evalbot.toml: {}", e))?;
         TgSvc {
             api: Api::new(&cfg.bot_id),
+            rate_limiter: Mutex::new(RateLimiter::new(cfg.rate_limit_user, cfg.rate_limit_chat)),
             config: cfg,
             whitelist: RwLock::new(wl),
             service: service,
@@ -459,6 +692,14 @@ impl TgSvc {
     async fn handle(self) {
         let me = Arc::new(self);
 
+        tokio::spawn(reload_on_sighup(me.clone()));
+        if let Some(interval) = me.config.config_watch_interval {
+            let me = me.clone();
+            tokio::spawn(async move {
+                me.service.watch(Duration::from_secs(interval)).await;
+            });
+        }
+
         let mut stream = me.api.stream();
         stream
             .timeout(Duration::from_secs(35))
@@ -483,3 +724,28 @@ async fn main() {
     tracing_subscriber::fmt::init();
     drop(TgSvc::run().await);
 }
+
+#[cfg(test)]
+mod test {
+    use super::split_input;
+
+    #[test]
+    fn test_split_input() {
+        assert_eq!(
+            split_input("echo hi\necho there"),
+            ("echo hi\necho there".to_owned(), None, vec![])
+        );
+        assert_eq!(
+            split_input("cat\n--- stdin\nline 1\nline 2"),
+            ("cat".to_owned(), Some("line 1\nline 2".to_owned()), vec![])
+        );
+        assert_eq!(
+            split_input("echo \"$@\"\n--- args\na b\nc"),
+            (
+                "echo \"$@\"".to_owned(),
+                None,
+                vec!["a".to_owned(), "b".to_owned(), "c".to_owned()]
+            )
+        );
+    }
+}
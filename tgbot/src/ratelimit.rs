@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// A token bucket: up to `burst` evaluations at once, refilling one every `interval` seconds.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub struct BucketCfg {
+    pub burst: u32,
+    pub interval: f64,
+}
+
+impl BucketCfg {
+    /// Checks that the bucket lets anything through, and refills at some point.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.burst == 0 {
+            return Err("burst must be at least 1".to_owned());
+        }
+        // also false for NaN
+        if !(self.interval > 0.0 && self.interval.is_finite()) {
+            return Err(format!(
+                "interval must be a positive number, not {}",
+                self.interval
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Don't bother pruning full buckets until there are this many.
+const PRUNE_THRESHOLD: usize = 1024;
+
+impl Bucket {
+    fn refill(&mut self, cfg: &BucketCfg, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed / cfg.interval).min(cfg.burst as f64);
+        self.last = now;
+    }
+
+    fn wait_time(&self, cfg: &BucketCfg) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) * cfg.interval).max(0.0))
+    }
+
+    fn is_full(&self, cfg: &BucketCfg) -> bool {
+        self.tokens >= cfg.burst as f64
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    cfg: BucketCfg,
+    buckets: HashMap<i64, Bucket>,
+}
+
+impl Buckets {
+    fn get(&mut self, id: i64, now: Instant) -> &mut Bucket {
+        if self.buckets.len() > PRUNE_THRESHOLD {
+            let cfg = self.cfg;
+            self.buckets.retain(|_, b| {
+                b.refill(&cfg, now);
+                !b.is_full(&cfg)
+            });
+        }
+        let cfg = self.cfg;
+        let bucket = self.buckets.entry(id).or_insert(Bucket {
+            tokens: cfg.burst as f64,
+            last: now,
+        });
+        bucket.refill(&cfg, now);
+        bucket
+    }
+}
+
+/// Rate limits keyed by user and by chat. An evaluation has to fit in both.
+#[derive(Debug)]
+pub struct RateLimiter {
+    users: Option<Buckets>,
+    chats: Option<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(user: Option<BucketCfg>, chat: Option<BucketCfg>) -> Self {
+        let buckets = |cfg| Buckets {
+            cfg,
+            buckets: HashMap::new(),
+        };
+        RateLimiter {
+            users: user.map(buckets),
+            chats: chat.map(buckets),
+        }
+    }
+
+    /// Takes a token for `user` in `chat`, or returns how long until one is available.
+    pub fn check(&mut self, user: i64, chat: i64) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut wait = Duration::from_secs(0);
+        for (buckets, id) in [(&mut self.users, user), (&mut self.chats, chat)] {
+            if let Some(buckets) = buckets {
+                let cfg = buckets.cfg;
+                let bucket = buckets.get(id, now);
+                if bucket.tokens < 1.0 {
+                    wait = wait.max(bucket.wait_time(&cfg));
+                }
+            }
+        }
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+        for (buckets, id) in [(&mut self.users, user), (&mut self.chats, chat)] {
+            if let Some(buckets) = buckets {
+                buckets.get(id, now).tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use super::{BucketCfg, Buckets, RateLimiter, PRUNE_THRESHOLD};
+
+    const CFG: BucketCfg = BucketCfg {
+        burst: 2,
+        interval: 10.0,
+    };
+
+    fn buckets() -> Buckets {
+        Buckets {
+            cfg: CFG,
+            buckets: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(CFG.validate().is_ok());
+        for &(burst, interval) in &[
+            (0, 10.0),
+            (2, 0.0),
+            (2, -1.0),
+            (2, f64::NAN),
+            (2, f64::INFINITY),
+        ] {
+            assert!(BucketCfg { burst, interval }.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_refill() {
+        let start = Instant::now();
+        let mut buckets = buckets();
+        let bucket = buckets.get(1, start);
+        assert_eq!(bucket.tokens, 2.0);
+        assert_eq!(bucket.wait_time(&CFG), Duration::from_secs(0));
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait_time(&CFG), Duration::from_secs(10));
+
+        let bucket = buckets.get(1, start + Duration::from_secs(5));
+        assert_eq!(bucket.tokens, 0.5);
+        assert_eq!(bucket.wait_time(&CFG), Duration::from_secs(5));
+
+        // the bucket holds no more than burst
+        let bucket = buckets.get(1, start + Duration::from_secs(100));
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn test_check() {
+        let mut limiter = RateLimiter::new(Some(CFG), None);
+        assert_eq!(limiter.check(1, 1), Ok(()));
+        assert_eq!(limiter.check(1, 2), Ok(()));
+        let wait = limiter.check(1, 1).unwrap_err();
+        assert!(wait > Duration::from_secs(9) && wait <= Duration::from_secs(10));
+        // other users have buckets of their own
+        assert_eq!(limiter.check(2, 1), Ok(()));
+
+        // a chat's bucket is shared by everyone in it
+        let mut limiter = RateLimiter::new(None, Some(BucketCfg { burst: 1, ..CFG }));
+        assert_eq!(limiter.check(1, 5), Ok(()));
+        assert!(limiter.check(2, 5).is_err());
+        assert_eq!(limiter.check(2, 6), Ok(()));
+    }
+
+    #[test]
+    fn test_prune() {
+        let start = Instant::now();
+        let mut buckets = buckets();
+        for id in 0..=PRUNE_THRESHOLD as i64 {
+            buckets.get(id, start).tokens -= 1.0;
+        }
+        // half refilled, so none of them can be forgotten yet
+        buckets.get(-1, start + Duration::from_secs(5));
+        assert_eq!(buckets.buckets.len(), PRUNE_THRESHOLD + 2);
+
+        // full buckets are the same as new ones, so they are forgotten
+        buckets.get(-2, start + Duration::from_secs(10));
+        assert_eq!(buckets.buckets.len(), 1);
+        assert!(buckets.buckets.contains_key(&-2));
+    }
+}